@@ -6,39 +6,21 @@ use portus::lang::Scope;
 #[derive(Clone, PartialEq, Eq)]
 pub enum ReportStatus {Report, NoReport, UrgentReport}
 
-// CCP may return before the specified time. This struct will aggregate relevant
-// values till the time is right
-pub struct AggMeasurement {
-    // In fraction of a (smoothed) RTT
-    reporting_interval: f32,
-    // For determining when to report
-    srtt: f32,
-    // EWMA variable
-    srtt_alpha: f32,
-    // Last time we reported
-    last_report_time: u64,
-    // Aggregate variables that are reset every measurement interval
-    acked: u32,
-    sacked: u32,
-    rtt: u32,
-    min_rtt: u32,
+/// Values of the fields of a single report from the datapath program
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Measurement {
+    pub acked: u32,
+    pub sacked: u32,
+    pub loss: u32,
+    pub was_timeout: bool,
+    pub inflight: u32,
+    pub rtt: u32,
+    pub min_rtt: u32,
+    pub now: u64,
 }
 
-impl AggMeasurement {
-    pub fn new(reporting_interval: f32) -> Self {
-        Self {
-            reporting_interval: reporting_interval,
-            srtt: 0.,
-            srtt_alpha: 1. / 16.,
-            last_report_time: 0,
-            acked: 0,
-            sacked: 0,
-            rtt: 0,
-            min_rtt: std::u32::MAX,
-        }
-    }
-
-    pub fn report(&mut self, m: Report, sc: &Scope) -> (ReportStatus, bool, u32, u32, u32, u32, u32, u32, u64) {
+impl Measurement {
+    pub fn from_report(m: &Report, sc: &Scope) -> Self {
         let acked = m.get_field("Report.acked", sc).expect(
             "expected acked field in returned measurement",
         ) as u32;
@@ -71,13 +53,67 @@ impl AggMeasurement {
             "expected minrtt field in returned measurement",
         ) as u32;
 
+        Self {
+            acked,
+            sacked,
+            loss,
+            was_timeout: was_timeout == 1,
+            inflight,
+            rtt,
+            min_rtt,
+            now,
+        }
+    }
+}
+
+// CCP may return before the specified time. This struct will aggregate relevant
+// values till the time is right
+pub struct AggMeasurement {
+    // In fraction of a (smoothed) RTT
+    reporting_interval: f32,
+    // For determining when to report
+    srtt: f32,
+    // EWMA variable
+    srtt_alpha: f32,
+    // Last time we reported
+    last_report_time: u64,
+    // Aggregate variables that are reset every measurement interval
+    acked: u32,
+    sacked: u32,
+    rtt: u32,
+    min_rtt: u32,
+}
+
+impl AggMeasurement {
+    pub fn new(reporting_interval: f32) -> Self {
+        Self {
+            reporting_interval: reporting_interval,
+            srtt: 0.,
+            srtt_alpha: 1. / 16.,
+            last_report_time: 0,
+            acked: 0,
+            sacked: 0,
+            rtt: 0,
+            min_rtt: std::u32::MAX,
+        }
+    }
+
+    pub fn report(&mut self, m: Measurement) -> (ReportStatus, bool, u32, u32, u32, u32, u32, u32, u64) {
+        let Measurement {acked, sacked, loss, was_timeout, inflight, rtt, min_rtt, now} = m;
+
         self.acked += acked;
         self.sacked = sacked;
         self.min_rtt = std::cmp::min(self.min_rtt, min_rtt);
 
-        if was_timeout == 1 || loss > 0 {
-            return (ReportStatus::UrgentReport, (was_timeout == 1), 0, 0, loss,
-                    0, 0, 0, now);
+        // Urgent reports carry what has been acked since the last report, and
+        // start the next one afresh, as a regular report would. See
+        // `Controller::on_report`
+        if was_timeout || loss > 0 {
+            let res = (ReportStatus::UrgentReport, was_timeout, self.acked, 0, loss,
+                       0, 0, self.min_rtt, now);
+            self.acked = 0;
+            self.min_rtt = u32::MAX;
+            return res;
         }
 
         if rtt != 0 {
@@ -102,3 +138,55 @@ impl AggMeasurement {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn measurement(acked: u32, loss: u32, min_rtt: u32, now: u64) -> Measurement {
+        Measurement {
+            acked,
+            loss,
+            rtt: min_rtt,
+            min_rtt,
+            now,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn urgent_report_carries_and_resets_what_was_acked() {
+        let mut agg = AggMeasurement::new(1.);
+        // The first measurement is reported at once
+        let res = agg.report(measurement(1448, 0, 40_000, 1_000_000));
+        assert!(res.0 == ReportStatus::Report);
+
+        let res = agg.report(measurement(2 * 1448, 0, 45_000, 1_001_000));
+        assert!(res.0 == ReportStatus::NoReport);
+        let res = agg.report(measurement(1448, 1, 42_000, 1_002_000));
+        assert!(res.0 == ReportStatus::UrgentReport);
+        let (_, was_timeout, acked, _, loss, _, _, min_rtt, now) = res;
+        assert_eq!((was_timeout, acked, loss, min_rtt, now), (false, 3 * 1448, 1, 42_000, 1_002_000));
+
+        // The next report starts afresh
+        let res = agg.report(measurement(1448, 0, 50_000, 1_010_000));
+        assert!(res.0 == ReportStatus::Report);
+        let (_, _, acked, _, loss, _, _, min_rtt, _) = res;
+        assert_eq!((acked, loss, min_rtt), (1448, 0, 50_000));
+    }
+
+    #[test]
+    fn timeout_is_reported_at_once() {
+        let mut agg = AggMeasurement::new(1.);
+        agg.report(measurement(1448, 0, 40_000, 1_000_000));
+        let res = agg.report(Measurement {
+            was_timeout: true,
+            min_rtt: u32::MAX,
+            now: 1_001_000,
+            ..Default::default()
+        });
+        assert!(res.0 == ReportStatus::UrgentReport);
+        let (_, was_timeout, acked, _, _, _, _, min_rtt, _) = res;
+        assert_eq!((was_timeout, acked, min_rtt), (true, 0, u32::MAX));
+    }
+}
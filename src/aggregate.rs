@@ -0,0 +1,53 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+
+use portus::DatapathInfo;
+
+use controller::{Controller, SharedController};
+
+/// Groups flows that share a path so that they share one controller, in the
+/// spirit of the Congestion Manager (RFC 3124). The aggregate probes the path
+/// as a single Copa flow would and splits its window evenly among members,
+/// instead of each flow building its own queue. Cloning gives another handle
+/// to the same groups.
+#[derive(Clone)]
+pub struct Aggregator {
+    // Flows with the same key belong to one aggregate
+    key: fn(&DatapathInfo) -> u64,
+    groups: Arc<Mutex<HashMap<u64, Weak<Mutex<Controller>>>>>,
+}
+
+impl Aggregator {
+    pub fn new(key: fn(&DatapathInfo) -> u64) -> Self {
+        Self {
+            key,
+            groups: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Treats all flows between the same pair of hosts as one aggregate, as
+    /// the Congestion Manager does by default. Flows to different services on
+    /// a host, or from several users behind one NAT, share a window too
+    pub fn key_by_host_pair(info: &DatapathInfo) -> u64 {
+        ((info.src_ip as u64) << 32) | info.dst_ip as u64
+    }
+
+    // Controller of the flow's aggregate, created with `new` if the aggregate
+    // has no live flows. Also returns whether the controller is new
+    pub(crate) fn join<F>(&self, info: &DatapathInfo, new: F) -> (SharedController, bool)
+    where
+        F: FnOnce() -> Controller,
+    {
+        let key = (self.key)(info);
+        let mut groups = self.groups.lock().unwrap();
+        // Forget aggregates whose flows have all ended
+        groups.retain(|_, ctl| ctl.upgrade().is_some());
+
+        if let Some(ctl) = groups.get(&key).and_then(|ctl| ctl.upgrade()) {
+            return (ctl, false);
+        }
+        let ctl = Arc::new(Mutex::new(new()));
+        groups.insert(key, Arc::downgrade(&ctl));
+        (ctl, true)
+    }
+}
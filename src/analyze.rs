@@ -0,0 +1,140 @@
+//! Summaries of how flows fared, from a recording made by `copa --record`.
+//! Modes, velocity, slow start and base RTT are as the flows recorded them
+//! after each report, so a summary is of what happened in the run, whatever
+//! code has changed since.
+
+use std::collections::HashMap;
+use std::io::{self, Read};
+
+use recorder::{FlowState, Reader, Record};
+use DeltaMode;
+
+/// How a flow fared over the recording. Times are in us
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FlowSummary {
+    pub sock_id: u32,
+    /// From the first measurement of the flow to its last
+    pub duration: u64,
+    pub bytes_acked: u64,
+    /// Queueing delay of each measurement with an RTT sample, i.e. its min
+    /// RTT less the base RTT at the time
+    pub queueing_delays: Vec<u32>,
+    /// Time spent in each mode, as listed in `DeltaMode::ALL`
+    pub mode_time: [u64; 6],
+    /// Times velocity fell back to 1 after having grown
+    pub num_velocity_resets: u32,
+    pub slow_start_time: u64,
+}
+
+impl FlowSummary {
+    /// Bytes per second
+    pub fn throughput(&self) -> f64 {
+        match self.duration {
+            0 => 0.,
+            d => self.bytes_acked as f64 * 1e6 / d as f64,
+        }
+    }
+
+    /// The given percentile (in [0, 1]) of queueing delay
+    pub fn queueing_delay_percentile(&self, p: f64) -> u32 {
+        if self.queueing_delays.is_empty() {
+            return 0;
+        }
+        let mut samples = self.queueing_delays.clone();
+        samples.sort_unstable();
+        let idx = std::cmp::min((p * samples.len() as f64) as usize, samples.len() - 1);
+        samples[idx]
+    }
+
+    pub fn mode_time(&self, mode: &DeltaMode) -> u64 {
+        let idx = DeltaMode::ALL.iter().position(|m| m == mode).unwrap();
+        self.mode_time[idx]
+    }
+
+    /// Named values to print and compare, in display units
+    pub fn metrics(&self) -> Vec<(String, f64)> {
+        let ms = |us: u64| us as f64 / 1e3;
+        let secs = |us: u64| us as f64 / 1e6;
+        let mut metrics = vec![
+            ("duration (s)".to_string(), secs(self.duration)),
+            (
+                "throughput (Mbit/s)".to_string(),
+                self.throughput() * 8. / 1e6,
+            ),
+        ];
+        for p in &[50, 95, 99] {
+            let delay = self.queueing_delay_percentile(*p as f64 / 100.);
+            metrics.push((format!("p{} queueing delay (ms)", p), ms(delay as u64)));
+        }
+        for (mode, time) in DeltaMode::ALL.iter().zip(self.mode_time.iter()) {
+            metrics.push((format!("time in {} mode (s)", mode.name()), secs(*time)));
+        }
+        metrics.push((
+            "velocity resets".to_string(),
+            self.num_velocity_resets as f64,
+        ));
+        metrics.push(("slow start (s)".to_string(), secs(self.slow_start_time)));
+        metrics
+    }
+}
+
+/// Summaries of the flows in a recording, in the order they started
+pub fn analyze<R: Read>(r: R) -> io::Result<Vec<FlowSummary>> {
+    let (mut reader, _, _) = Reader::new(r)?;
+    let mut summaries: Vec<FlowSummary> = Vec::new();
+    // Per flow, the index of its summary
+    let mut index = HashMap::new();
+    // Per flow, the time and min RTT of the measurement it is acting on
+    let mut pending = HashMap::new();
+    // Per flow, its time and state as of its last report
+    let mut last: HashMap<u32, (u64, FlowState)> = HashMap::new();
+    while let Some(record) = reader.next_record()? {
+        match record {
+            Record::Flow(info) => {
+                // Socket ids are reused once a flow ends
+                index.insert(info.sock_id, summaries.len());
+                pending.remove(&info.sock_id);
+                last.remove(&info.sock_id);
+                summaries.push(FlowSummary {
+                    sock_id: info.sock_id,
+                    ..Default::default()
+                });
+            }
+            Record::Measurement(sock_id, m) => {
+                if let Some(&idx) = index.get(&sock_id) {
+                    summaries[idx].bytes_acked += m.acked as u64;
+                    pending.insert(sock_id, (m.now, m.min_rtt));
+                }
+            }
+            Record::State(sock_id, state) => {
+                let (idx, (now, min_rtt)) = match (index.get(&sock_id), pending.remove(&sock_id)) {
+                    (Some(&idx), Some(m)) => (idx, m),
+                    _ => continue,
+                };
+                let summary = &mut summaries[idx];
+                if min_rtt != 0 && min_rtt != u32::MAX && state.base_rtt != u32::MAX {
+                    summary
+                        .queueing_delays
+                        .push(min_rtt.saturating_sub(state.base_rtt));
+                }
+                // Time since the last report is put down to the state the
+                // flow was in during it
+                if let Some((prev_now, prev)) = last.get(&sock_id) {
+                    let dt = now.saturating_sub(*prev_now);
+                    summary.duration += dt;
+                    let mode_idx = DeltaMode::ALL.iter().position(|m| *m == prev.mode).unwrap();
+                    summary.mode_time[mode_idx] += dt;
+                    if prev.slow_start {
+                        summary.slow_start_time += dt;
+                    }
+                    if prev.velocity > 1 && state.velocity == 1 {
+                        summary.num_velocity_resets += 1;
+                    }
+                }
+                last.insert(sock_id, (now, state));
+            }
+            Record::Update(..) => {}
+        }
+    }
+    Ok(summaries)
+}
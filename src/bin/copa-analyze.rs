@@ -0,0 +1,62 @@
+extern crate clap;
+use clap::Arg;
+
+extern crate ccp_copa;
+use ccp_copa::analyze::{self, FlowSummary};
+
+use std::fs::File;
+use std::io::BufReader;
+use std::process;
+
+fn load(path: &str) -> Vec<FlowSummary> {
+    File::open(path)
+        .and_then(|f| analyze::analyze(BufReader::new(f)))
+        .unwrap_or_else(|e| {
+            eprintln!("could not analyze {}: {}", path, e);
+            process::exit(2);
+        })
+}
+
+fn main() {
+    let matches = clap::App::new("CCP Copa analyze")
+        .version("0.1.0")
+        .author("Venkat Arun <venkatar@mit.edu>")
+        .about("Summarizes how each flow in a recording made by copa --record fared, or compares two recordings")
+        .arg(Arg::with_name("recording")
+             .help("File written by copa --record")
+             .required(true))
+        .arg(Arg::with_name("baseline")
+             .long("diff")
+             .takes_value(true)
+             .help("Another recording to compare against, flow by flow in the order flows started"))
+        .get_matches();
+
+    let flows = load(matches.value_of("recording").unwrap());
+    let baseline = match matches.value_of("baseline") {
+        Some(path) => load(path),
+        None => {
+            for flow in flows.iter() {
+                println!("flow {}", flow.sock_id);
+                for (name, value) in flow.metrics() {
+                    println!("  {:<32} {:>10.3}", name, value);
+                }
+            }
+            return;
+        }
+    };
+
+    if flows.len() != baseline.len() {
+        println!("warning: {} flows against {} in the baseline, comparing the first {}",
+                 flows.len(), baseline.len(), std::cmp::min(flows.len(), baseline.len()));
+    }
+    for (flow, base) in flows.iter().zip(baseline.iter()) {
+        println!("flow {} (baseline flow {})", flow.sock_id, base.sock_id);
+        for ((name, value), (_, base_value)) in flow.metrics().into_iter().zip(base.metrics()) {
+            let change = match base_value == 0. {
+                true => String::new(),
+                false => format!("{:+.1}%", (value - base_value) * 100. / base_value),
+            };
+            println!("  {:<32} {:>10.3} {:>10.3} {:>8}", name, base_value, value, change);
+        }
+    }
+}
@@ -0,0 +1,46 @@
+extern crate clap;
+use clap::Arg;
+
+extern crate ccp_copa;
+use ccp_copa::recorder;
+
+use std::fs::File;
+use std::io::BufReader;
+use std::process;
+
+fn main() {
+    let matches = clap::App::new("CCP Copa replay")
+        .version("0.1.0")
+        .author("Venkat Arun <venkatar@mit.edu>")
+        .about("Replays a recording made by copa --record and checks that Copa makes the same decisions")
+        .arg(Arg::with_name("recording")
+             .help("File written by copa --record")
+             .required(true))
+        .get_matches();
+
+    let path = matches.value_of("recording").unwrap();
+    let summary = File::open(path)
+        .and_then(|f| recorder::replay(BufReader::new(f)))
+        .unwrap_or_else(|e| {
+            eprintln!("could not replay {}: {}", path, e);
+            process::exit(2);
+        });
+
+    println!(
+        "replayed {} flows, {} measurements, {} updates",
+        summary.num_flows, summary.num_measurements, summary.num_updates
+    );
+    if summary.shared {
+        println!("warning: recorded flows shared state with each other or were under flow policies, so replay may differ");
+    }
+    match summary.divergence {
+        None => println!("all updates identical"),
+        Some(d) => {
+            println!(
+                "diverged at update {} of flow {}: expected {:?}, got {:?}",
+                d.index, d.sock_id, d.expected, d.got
+            );
+            process::exit(1);
+        }
+    }
+}
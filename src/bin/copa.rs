@@ -19,7 +19,7 @@ fn make_logger() -> slog::Logger {
     slog::Logger::root(drain, o!())
 }
 
-fn make_args(log: slog::Logger) -> Result<(CopaConfig, String), std::num::ParseIntError> {
+fn make_args(log: slog::Logger) -> Result<(CopaConfig, String), Box<dyn std::error::Error>> {
     let matches = clap::App::new("CCP Copa")
         .version("0.1.0")
         .author("Venkat Arun <venkatar@mit.edu>")
@@ -37,19 +37,195 @@ fn make_args(log: slog::Logger) -> Result<(CopaConfig, String), std::num::ParseI
              .long("default_delta")
              .help("Delta to use when in default mode.")
              .default_value("0.5"))
+        .arg(Arg::with_name("target_delay")
+             .long("target_delay")
+             .takes_value(true)
+             .help("Milliseconds of queueing delay to aim for, adapting delta to it instead of using default_delta."))
+        .arg(Arg::with_name("scavenger")
+             .long("scavenger")
+             .takes_value(true)
+             .conflicts_with("target_delay")
+             .help("Yield to all other traffic, backing off whenever queueing delay exceeds this many milliseconds. For bulk transfers that should never slow down others."))
+        .arg(Arg::with_name("loss_delta_min")
+             .long("loss_delta_min")
+             .takes_value(true)
+             .help("Delta that loss mode recovers to once losses stop. Defaults to default_delta."))
+        .arg(Arg::with_name("loss_delta_max")
+             .long("loss_delta_max")
+             .help("Largest delta loss mode backs off to.")
+             .default_value("1.0"))
+        .arg(Arg::with_name("loss_delta_step")
+             .long("loss_delta_step")
+             .help("Amount by which delta comes down in loss mode for every RTT without loss.")
+             .default_value("0.05"))
+        .arg(Arg::with_name("idle_restart")
+             .long("idle_restart")
+             .help("Window to use when resuming after idle: (reset|decay|keep)")
+             .possible_values(&["reset", "decay", "keep"])
+             .default_value("decay"))
+        .arg(Arg::with_name("slow_start")
+             .long("slow_start")
+             .help("How to leave slow start. 'classic' leaves as soon as one report says the window should not grow, 'hystart' once several rounds say so or acks stop arriving faster.")
+             .possible_values(&["classic", "hystart"])
+             .default_value("classic"))
+        .arg(Arg::with_name("slow_start_rounds")
+             .long("slow_start_rounds")
+             .help("Number of RTT rounds in a row that must say to leave slow start, with hystart.")
+             .default_value("2"))
+        .arg(Arg::with_name("slow_start_max_growth")
+             .long("slow_start_max_growth")
+             .help("Most the window may grow by per RTT in slow start, with hystart. Classic slow start doubles it.")
+             .default_value("1.5"))
+        .arg(Arg::with_name("loss_response")
+             .long("loss_response")
+             .help("How the window responds to loss. 'none' leaves it to delay control, 'decrease' cuts it at most once per RTT, 'prr' brings it down over the next RTT.")
+             .possible_values(&["none", "decrease", "prr"])
+             .default_value("none"))
+        .arg(Arg::with_name("loss_beta")
+             .long("loss_beta")
+             .help("Factor the window is cut by on loss, with decrease or prr.")
+             .default_value("0.7"))
+        .arg(Arg::with_name("rtt_history")
+             .long("rtt_history")
+             .help("Seconds of RTT history over which base RTT is the minimum. It is at least 30 RTTs regardless.")
+             .default_value("10"))
+        .arg(Arg::with_name("detect_route_changes")
+             .long("detect_route_changes")
+             .help("Re-estimate base RTT as soon as the path is seen to have gotten longer, rather than once older samples age out."))
+        .arg(Arg::with_name("path_cache_ttl")
+             .long("path_cache_ttl")
+             .help("Seconds for which a new flow may reuse base RTT and cwnd learnt by earlier flows to the same destination. Setting 0 disables the cache.")
+             .default_value("0"))
+        .arg(Arg::with_name("path_cache_prefix")
+             .long("path_cache_prefix")
+             .help("Length of the destination address prefix that flows must share to reuse each other's path state.")
+             .default_value("32"))
+        .arg(Arg::with_name("weight")
+             .long("weight")
+             .help("Share of the bottleneck relative to other Copa flows. Scales delta by 1/weight.")
+             .default_value("1.0"))
+        .arg(Arg::with_name("aggregate")
+             .long("aggregate")
+             .help("Make flows between the same pair of hosts share a single controller."))
+        .arg(Arg::with_name("policy_file")
+             .long("policy_file")
+             .takes_value(true)
+             .help("File of per-flow rate and window bounds, one `key=value ...` line per policy, e.g. 'dst_port=873 max_rate=1000000'. It is read once at startup, so changing it means restarting."))
+        .arg(Arg::with_name("record")
+             .long("record")
+             .takes_value(true)
+             .help("Records every report and decision to this file, for replay with copa-replay and summaries with copa-analyze."))
+        .arg(Arg::with_name("flight_recorder")
+             .long("flight_recorder")
+             .help("Number of recent reports per flow to keep and dump on timeouts and anomalies. Setting 0, the default, disables it.")
+             .default_value("0"))
+        .arg(Arg::with_name("flight_recorder_dir")
+             .long("flight_recorder_dir")
+             .takes_value(true)
+             .help("Directory to dump flight recorder history to, instead of the log."))
+        .arg(Arg::with_name("probe_rtt_interval")
+             .long("probe_rtt_interval")
+             .help("Seconds between periods in which flows cut their window to drain the queue and measure base RTT afresh. Setting 0 disables probing.")
+             .default_value("0"))
+        .arg(Arg::with_name("probe_rtt_duration")
+             .long("probe_rtt_duration")
+             .help("Milliseconds a probe lasts, if longer than two base RTTs.")
+             .default_value("200"))
+        .arg(Arg::with_name("probe_rtt_cwnd")
+             .long("probe_rtt_cwnd")
+             .help("Window in packets while probing.")
+             .default_value("4"))
         .get_matches();
 
-    Ok((
-        ccp_copa::CopaConfig {
-            logger: Some(log),
-            init_cwnd: u32::from_str_radix(matches.value_of("init_cwnd").unwrap(), 10)?,
-            default_delta: (matches.value_of("default_delta").unwrap())
-                .parse()
-                .unwrap(),
-            delta_mode: ccp_copa::DeltaModeConf::Auto,
+    let path_cache_ttl: u64 = matches.value_of("path_cache_ttl").unwrap().parse()?;
+    let path_cache_prefix: u8 = matches.value_of("path_cache_prefix").unwrap().parse()?;
+    let path_cache = match path_cache_ttl {
+        0 => None,
+        ttl => Some(ccp_copa::PathCache::new(
+            path_cache_prefix,
+            std::time::Duration::from_secs(ttl),
+        )),
+    };
+
+    let default_delta: f32 = matches.value_of("default_delta").unwrap().parse()?;
+    let mut cfg = ccp_copa::CopaConfig {
+        logger: Some(log),
+        init_cwnd: matches.value_of("init_cwnd").unwrap().parse()?,
+        default_delta,
+        delta_mode: match (matches.value_of("target_delay"), matches.value_of("scavenger")) {
+            (Some(target), _) => ccp_copa::DeltaModeConf::TargetDelay {
+                target: (target.parse::<f64>()? * 1000.) as u32,
+            },
+            (_, Some(target)) => ccp_copa::DeltaModeConf::Scavenger {
+                target: (target.parse::<f64>()? * 1000.) as u32,
+            },
+            _ => ccp_copa::DeltaModeConf::Auto,
+        },
+        loss_delta: ccp_copa::LossDeltaConf {
+            min: match matches.value_of("loss_delta_min") {
+                Some(min) => min.parse()?,
+                None => default_delta,
+            },
+            max: matches.value_of("loss_delta_max").unwrap().parse()?,
+            step: matches.value_of("loss_delta_step").unwrap().parse()?,
+        },
+        idle_restart: match matches.value_of("idle_restart").unwrap() {
+            "reset" => ccp_copa::IdleRestartConf::Reset,
+            "keep" => ccp_copa::IdleRestartConf::Keep,
+            _ => ccp_copa::IdleRestartConf::Decay,
+        },
+        slow_start: match matches.value_of("slow_start").unwrap() {
+            "hystart" => ccp_copa::SlowStartConf::HyStart {
+                rounds: matches.value_of("slow_start_rounds").unwrap().parse()?,
+                max_growth: matches.value_of("slow_start_max_growth").unwrap().parse()?,
+            },
+            _ => ccp_copa::SlowStartConf::Classic,
         },
-        String::from(matches.value_of("ipc").unwrap()),
-    ))
+        loss_response: match matches.value_of("loss_response").unwrap() {
+            "decrease" => ccp_copa::LossResponseConf::Decrease {
+                beta: matches.value_of("loss_beta").unwrap().parse()?,
+            },
+            "prr" => ccp_copa::LossResponseConf::Prr {
+                beta: matches.value_of("loss_beta").unwrap().parse()?,
+            },
+            _ => ccp_copa::LossResponseConf::None,
+        },
+        rtt_history: (matches.value_of("rtt_history").unwrap().parse::<f64>()? * 1e6) as u64,
+        detect_route_changes: matches.is_present("detect_route_changes"),
+        path_cache,
+        weight: matches.value_of("weight").unwrap().parse()?,
+        aggregator: match matches.is_present("aggregate") {
+            true => Some(ccp_copa::Aggregator::new(ccp_copa::Aggregator::key_by_host_pair)),
+            false => None,
+        },
+        multipath: None,
+        recorder: None,
+        flight_recorder: match matches.value_of("flight_recorder").unwrap().parse()? {
+            0 => None,
+            capacity => Some(ccp_copa::FlightRecorderConf {
+                capacity,
+                dump_dir: matches.value_of("flight_recorder_dir").map(std::path::PathBuf::from),
+            }),
+        },
+        probe_rtt: match matches.value_of("probe_rtt_interval").unwrap().parse::<f64>()? {
+            interval if interval > 0. => Some(ccp_copa::ProbeRttConf {
+                interval: (interval * 1e6) as u64,
+                duration: matches.value_of("probe_rtt_duration").unwrap().parse::<u64>()? * 1000,
+                cwnd: matches.value_of("probe_rtt_cwnd").unwrap().parse()?,
+            }),
+            _ => None,
+        },
+        policies: match matches.value_of("policy_file") {
+            Some(path) => Some(ccp_copa::PolicyTable::from_file(path)?),
+            None => None,
+        },
+    };
+    if let Some(path) = matches.value_of("record") {
+        let f = std::io::BufWriter::new(std::fs::File::create(path)?);
+        cfg.recorder = Some(ccp_copa::recorder::Recorder::new(f, &cfg)?);
+    }
+
+    Ok((cfg, String::from(matches.value_of("ipc").unwrap())))
 }
 
 fn main() {
@@ -63,6 +239,19 @@ fn main() {
           "init_cwnd" => cfg.init_cwnd,
           "default_delta" => cfg.default_delta,
           "delta_mode" => ?cfg.delta_mode,
+          "loss_delta" => ?cfg.loss_delta,
+          "idle_restart" => ?cfg.idle_restart,
+          "slow_start" => ?cfg.slow_start,
+          "loss_response" => ?cfg.loss_response,
+          "rtt_history" => cfg.rtt_history,
+          "detect_route_changes" => cfg.detect_route_changes,
+          "path_cache" => cfg.path_cache.is_some(),
+          "weight" => cfg.weight,
+          "aggregate" => cfg.aggregator.is_some(),
+          "record" => cfg.recorder.is_some(),
+          "flight_recorder" => ?cfg.flight_recorder,
+          "probe_rtt" => ?cfg.probe_rtt,
+          "policies" => cfg.policies.is_some(),
     );
 
     portus::start!(ipc.as_str(), Some(log), cfg).unwrap()
@@ -0,0 +1,620 @@
+use std::sync::{Arc, Mutex};
+
+use agg_measurement::ReportStatus;
+use delta_manager::{DeltaManager, DeltaMode, DeltaModeConf, ModeTransition};
+use invariant;
+use multipath::Subflow;
+use rtt_window::RTTWindow;
+use slow_start::SlowStart;
+use {CopaConfig, IdleRestartConf, LossResponseConf};
+
+// Copa's control law. A controller usually drives a single flow, but flows in
+// an aggregate share one, in which case it sees the reports of all of them
+pub struct Controller {
+    logger: Option<slog::Logger>,
+    delta_manager: DeltaManager,
+    // Time of the latest report from any of our flows
+    prev_report_time: u64,
+    cwnd: u32,
+    init_cwnd: u32,
+    // The window never goes below this
+    min_cwnd: u32,
+    // `min_cwnd` when the controller has a single flow
+    flow_min_cwnd: u32,
+    // Socket ids of the flows sharing the controller, in the order they joined
+    flows: Vec<u32>,
+    slow_start: bool,
+    slow_start_exit: SlowStart,
+    rtt_win: RTTWindow,
+    velocity: u32,
+    cur_direction: i64,
+    prev_direction: i64,
+    time_since_direction: u64,
+    prev_update_rtt: u64,
+    idle_restart: IdleRestartConf,
+    loss_response: LossResponseConf,
+    // Time we last reduced the window due to loss
+    prev_loss_response: u64,
+    recovery: Option<Recovery>,
+    rtt_history: u64,
+    detect_route_changes: bool,
+    weight: f32,
+    // Set if we are a subflow of a multipath connection
+    subflow: Option<Subflow>,
+    // Whether our flow is probing RTT, with its window held down regardless
+    probing: bool,
+    // Invariant found violated while handling the current report
+    violation: Option<&'static str>,
+    num_violations: u32,
+    num_route_changes: u32,
+}
+
+pub type SharedController = Arc<Mutex<Controller>>;
+
+// Window reduction in progress after a loss, when responding as PRR does
+struct Recovery {
+    // Window when the loss was detected, and the one to end up with
+    recover_fs: u32,
+    ssthresh: u32,
+    // Bytes delivered since
+    delivered: u32,
+}
+
+impl Controller {
+    pub fn new(cfg: &CopaConfig, init_cwnd: u32, subflow: Option<Subflow>) -> Self {
+        if cfg.weight <= 0. {
+            panic!("Weight should be positive.");
+        }
+        if cfg.aggregator.is_some() && cfg.multipath.is_some() {
+            panic!("Flows should not be both aggregated and coupled as multipath subflows.");
+        }
+        // A scavenger must be able to back off further than a window that, on
+        // a short path, would be a good part of the bandwidth
+        let flow_min_cwnd = match cfg.delta_mode {
+            DeltaModeConf::Scavenger { .. } => std::cmp::min(init_cwnd, 2 * 1448),
+            _ => init_cwnd,
+        };
+        Self {
+            logger: cfg.logger.clone(),
+            delta_manager: DeltaManager::new(
+                cfg.default_delta,
+                cfg.delta_mode.clone(),
+                cfg.loss_delta.clone(),
+            ),
+            prev_report_time: 0,
+            cwnd: init_cwnd,
+            init_cwnd,
+            min_cwnd: flow_min_cwnd,
+            flow_min_cwnd,
+            flows: Vec::new(),
+            slow_start: true,
+            slow_start_exit: SlowStart::new(cfg.slow_start.clone()),
+            rtt_win: RTTWindow::new(cfg.rtt_history, cfg.detect_route_changes),
+            velocity: 1,
+            cur_direction: 0,
+            prev_direction: 0,
+            time_since_direction: 0,
+            prev_update_rtt: 0,
+            idle_restart: cfg.idle_restart.clone(),
+            loss_response: cfg.loss_response.clone(),
+            prev_loss_response: 0,
+            recovery: None,
+            rtt_history: cfg.rtt_history,
+            detect_route_changes: cfg.detect_route_changes,
+            weight: cfg.weight,
+            subflow,
+            probing: false,
+            violation: None,
+            num_violations: 0,
+            num_route_changes: 0,
+        }
+    }
+
+    // The window is split evenly among the flows of an aggregate, so it must
+    // leave each of them at least two packets
+    pub fn add_flow(&mut self, sock_id: u32) {
+        self.flows.push(sock_id);
+        self.min_cwnd = std::cmp::max(self.flow_min_cwnd, self.flows.len() as u32 * 2 * 1448);
+        self.cwnd = std::cmp::max(self.cwnd, self.min_cwnd);
+    }
+
+    pub fn remove_flow(&mut self, sock_id: u32) {
+        self.flows.retain(|&id| id != sock_id);
+        self.min_cwnd = std::cmp::max(self.flow_min_cwnd, self.flows.len() as u32 * 2 * 1448);
+    }
+
+    // A flow's part of the window. Flows of an aggregate get whole packets,
+    // with those left over going to the flows that joined first, so that the
+    // parts add up to the window rather than each losing a fraction of a
+    // packet
+    pub fn share(&self, sock_id: u32) -> u32 {
+        let num_flows = self.flows.len() as u32;
+        if num_flows <= 1 {
+            return self.cwnd;
+        }
+        let pkts = self.cwnd / 1448;
+        let rank = self.flows.iter().position(|&id| id == sock_id).unwrap_or(0) as u32;
+        let extra = if rank < pkts % num_flows { 1 } else { 0 };
+        (pkts / num_flows + extra) * 1448
+    }
+
+    // Start from a base RTT and window learnt elsewhere
+    pub fn seed(&mut self, base_rtt: u32, cwnd: u32) {
+        self.rtt_win.seed_base_rtt(base_rtt);
+        self.cwnd = std::cmp::max(self.cwnd, cwnd);
+    }
+
+    pub fn get_cwnd(&self) -> u32 {
+        self.cwnd
+    }
+
+    pub fn get_base_rtt(&self) -> u32 {
+        self.rtt_win.get_base_rtt()
+    }
+
+    pub fn get_delta(&self) -> f32 {
+        self.delta_manager.get_delta()
+    }
+
+    pub fn get_mode(&self) -> DeltaMode {
+        self.delta_manager.get_mode()
+    }
+
+    pub fn take_mode_transitions(&mut self) -> Vec<ModeTransition> {
+        self.delta_manager.take_transitions()
+    }
+
+    pub fn get_queue_delay(&self) -> u32 {
+        self.delta_manager.get_queue_delay()
+    }
+
+    pub fn get_target_delay(&self) -> Option<u32> {
+        self.delta_manager.get_target_delay()
+    }
+
+    pub fn get_loss_rate(&self) -> f32 {
+        self.delta_manager.get_loss_rate()
+    }
+
+    pub fn get_congestive_loss_rate(&self) -> f32 {
+        self.delta_manager.get_congestive_loss_rate()
+    }
+
+    pub fn get_velocity(&self) -> u32 {
+        self.velocity
+    }
+
+    pub fn in_slow_start(&self) -> bool {
+        self.slow_start
+    }
+
+    pub fn get_num_violations(&self) -> u32 {
+        self.num_violations
+    }
+
+    pub fn get_num_route_changes(&self) -> u32 {
+        self.num_route_changes
+    }
+
+    // Competing with BBR, drain our part of the queue along with BBR's when it
+    // probes RTT. Else it measures our queue as part of the path, and keeps
+    // ever more in flight
+    pub fn bbr_probe_rtt_due(&self) -> bool {
+        self.delta_manager.get_mode() == DeltaMode::BBRCoop && self.rtt_win.bbr_probe_rtt_due()
+    }
+
+    pub fn note_own_probe_rtt(&mut self, now: u64) {
+        self.rtt_win.note_own_probe_rtt(now);
+    }
+
+    // The queue drains while we probe RTT, which the control law would take
+    // as room to grow the window into, and then dump on the queue once the
+    // probe ends. So it is left as it was, and RTT samples only go towards
+    // base RTT
+    pub fn set_probing(&mut self, probing: bool) {
+        self.probing = probing;
+    }
+
+    // Hold the window to at most `max`, as a flow policy asks. Otherwise the
+    // control law keeps growing a window the flow isn't allowed to use
+    pub fn limit_cwnd(&mut self, max: u32) {
+        if self.cwnd > max {
+            self.cwnd = std::cmp::max(max, self.min_cwnd);
+            self.slow_start = false;
+            self.velocity = 1;
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn on_report(
+        &mut self,
+        report_status: ReportStatus,
+        was_timeout: bool,
+        acked: u32,
+        sacked: u32,
+        loss: u32,
+        min_rtt: u32,
+        now: u64,
+    ) {
+        if self.prev_report_time != 0 && now > self.prev_report_time {
+            self.handle_idle(now - self.prev_report_time, now);
+        }
+        self.prev_report_time = now;
+        if loss > 0 {
+            self.rtt_win.note_loss(now);
+        }
+
+        if report_status == ReportStatus::UrgentReport {
+            if was_timeout {
+                self.handle_timeout();
+            } else if loss > 0 {
+                self.handle_loss(now);
+            }
+
+            // When most reports from the datapath have a loss in them, as with
+            // heavy random loss or an AQM that drops steadily, a regular
+            // report may not come for seconds. So the acks and RTT that came
+            // along with a loss are acted on here: without them the loss rate
+            // reads as all loss, and the window stays where it is
+            if was_timeout || acked == 0 || min_rtt == u32::MAX {
+                self.delta_manager
+                    .report_measurement(&mut self.rtt_win, acked, loss, min_rtt, now);
+            } else {
+                self.on_delivered(acked, loss, min_rtt, now);
+            }
+        } else if report_status == ReportStatus::NoReport || acked + loss + sacked == 0 {
+            // Do nothing
+        } else {
+            self.on_delivered(acked, loss, min_rtt, now);
+        }
+
+        if let Some(what) = self.violation.take().or_else(|| self.rtt_win.take_violation()) {
+            self.reset(what);
+        }
+    }
+
+    // Act on acks that came with an RTT sample
+    fn on_delivered(&mut self, acked: u32, loss: u32, min_rtt: u32, now: u64) {
+        // Record RTT
+        self.rtt_win.new_rtt_sample(min_rtt, now);
+        if let Some((old_base_rtt, base_rtt)) = self.rtt_win.take_route_change() {
+            self.num_route_changes += 1;
+            if let Some(log) = self.logger.as_ref() {
+                info!(log, "route change";
+                    "old_base_rtt" => old_base_rtt,
+                    "base_rtt" => base_rtt,
+                    "num_route_changes" => self.num_route_changes,
+                );
+            }
+        }
+        // Update delta mode and delta
+        self.delta_manager
+            .report_measurement(&mut self.rtt_win, acked, loss, min_rtt, now);
+
+        // Increase/decrease the cwnd corresponding to new measurements,
+        // unless we are still reducing it after a loss
+        if !self.recover(acked) && !self.probing {
+            self.delay_control(min_rtt, acked, now);
+        }
+    }
+
+    // Our state is inconsistent, so start over as a new flow would rather than
+    // take the agent down with every other flow
+    fn reset(&mut self, what: &'static str) {
+        self.num_violations += 1;
+        if let Some(log) = self.logger.as_ref() {
+            warn!(log, "invariant violated, resetting controller";
+                "what" => what,
+                "curr_cwnd (pkts)" => self.cwnd / 1448,
+                "velocity" => self.velocity,
+                "total_violations" => invariant::num_invariant_violations(),
+            );
+        }
+
+        self.cwnd = std::cmp::max(self.init_cwnd, self.min_cwnd);
+        self.enter_slow_start();
+        self.recovery = None;
+        self.rtt_win = RTTWindow::new(self.rtt_history, self.detect_route_changes);
+        self.delta_manager.reset();
+        self.velocity = 1;
+        self.cur_direction = 0;
+        self.prev_direction = 0;
+        self.time_since_direction = 0;
+        self.prev_update_rtt = 0;
+    }
+
+    fn delay_control(&mut self, rtt: u32, actual_acked: u32, now: u64) {
+        // A subflow compares the rate of its whole connection against the
+        // target for its own path, so that the connection as a whole takes a
+        // single flow's share and moves traffic to less delayed paths. The
+        // window changes in proportion to the connection's window, so that it
+        // grows like a single flow's would
+        let (total_cwnd, equiv_cwnd) = match self.subflow.as_ref() {
+            Some(subflow) => {
+                subflow.update(self.cwnd, rtt);
+                let (total_cwnd, total_rate) = subflow.totals();
+                (total_cwnd, total_rate * rtt as f64)
+            }
+            None => (self.cwnd as u64, self.cwnd as f64),
+        };
+
+        let increase = rtt as u64 * 1460u64
+            > (((rtt - self.rtt_win.get_base_rtt()) as f64)
+                * (self.delta_manager.get_delta() / self.weight) as f64
+                * equiv_cwnd) as u64;
+
+        let mut acked = actual_acked;
+        // Just in case. Sometimes CCP returns after significantly longer than
+        // what was asked for. In that case, actual_acked can be huge
+        if actual_acked > self.cwnd {
+            acked = self.cwnd;
+        }
+        // Update velocity
+        if increase {
+            self.cur_direction += 1;
+        } else {
+            self.cur_direction -= 1;
+        }
+
+        if self.velocity > 1
+            && ((increase && self.prev_direction < 0) || (!increase && self.prev_direction > 0))
+        {
+            self.velocity = 1;
+            self.time_since_direction = now;
+        }
+
+        if now - self.prev_update_rtt >= 2 * rtt as u64 && !self.slow_start {
+            // TODO(venkatar): Time (now) may be a u32 internally, which means it
+            // will wrap around. Handle this.
+            if (self.prev_direction > 0 && self.cur_direction > 0)
+                || (self.prev_direction < 0 && self.cur_direction < 0)
+            {
+                if (now - self.time_since_direction) as u32 > 3 * rtt {
+                    self.velocity *= 2;
+                } else if self.velocity > 1 {
+                    // The RTT has grown since velocity last doubled, so it
+                    // doubled sooner than it now should have. Start afresh
+                    self.velocity = 1;
+                    self.time_since_direction = now;
+                }
+            } else {
+                self.velocity = 1;
+                self.time_since_direction = now;
+            }
+            if self.velocity > 0xffff {
+                self.velocity = 0xffff;
+            }
+            self.prev_direction = self.cur_direction;
+            self.cur_direction = 0;
+            self.prev_update_rtt = now;
+        }
+
+        // Change window
+        if self.slow_start {
+            let srtt = self.rtt_win.get_srtt();
+            if self.slow_start_exit.on_report(increase, acked, self.cwnd, srtt, now) {
+                self.slow_start = false;
+            } else if increase {
+                let max_cwnd = std::cmp::max(self.cwnd, self.slow_start_exit.max_cwnd());
+                self.cwnd = std::cmp::min(self.cwnd + acked, max_cwnd);
+            }
+        } else {
+            let mut velocity = 1u64;
+            if (increase && self.prev_direction > 0) || (!increase && self.prev_direction < 0) {
+                velocity = self.velocity as u64;
+            }
+
+            // If we are in TCP mode, delta changes with time. Account for that.
+            // A scavenger's large delta sets where the window should be, but
+            // would make it get there slowly, so it moves as fast as a flow
+            // with delta 1
+            let delta = match self.delta_manager.get_mode() {
+                DeltaMode::TCPCoop if !increase => 1. / (1. + 1. / self.delta_manager.get_delta()),
+                DeltaMode::Scavenger => self.delta_manager.get_delta().min(1.),
+                _ => self.delta_manager.get_delta(),
+            } / self.weight;
+
+            // Do computations in u64 to avoid overflow. Multiply first so
+            // integer division doesn't cause as many problems
+            let change =
+                (velocity * 1448 * (acked as u64) / (total_cwnd as f32 * delta) as u64) as u32;
+
+            if increase {
+                self.cwnd += change;
+            } else {
+                if change + self.min_cwnd > self.cwnd {
+                    self.cwnd = self.min_cwnd;
+                    self.velocity = 1;
+                    self.time_since_direction = now;
+                } else {
+                    self.cwnd -= change;
+                }
+            }
+        }
+        if !invariant::check(self.cwnd >= self.min_cwnd, "cwnd below min_cwnd") {
+            self.violation = Some("cwnd below min_cwnd");
+        }
+    }
+
+    fn enter_slow_start(&mut self) {
+        self.slow_start = true;
+        self.slow_start_exit.restart();
+    }
+
+    // We don't track RTT variance, so use RFC 6298's estimate for when there
+    // is only one sample
+    fn rto(&self) -> u64 {
+        std::cmp::max(200_000, 3 * self.rtt_win.get_srtt() as u64)
+    }
+
+    fn handle_idle(&mut self, idle_time: u64, now: u64) {
+        if self.rtt_win.get_srtt() == 0 || idle_time <= self.rto() {
+            return;
+        }
+
+        let prev_cwnd = self.cwnd;
+        match self.idle_restart {
+            IdleRestartConf::Reset => {
+                self.cwnd = std::cmp::max(self.init_cwnd, self.min_cwnd);
+                self.enter_slow_start();
+            }
+            IdleRestartConf::Decay => {
+                let num_rto = std::cmp::min(idle_time / self.rto(), 32);
+                self.cwnd = std::cmp::max(self.min_cwnd, (self.cwnd as u64 >> num_rto) as u32);
+                self.enter_slow_start();
+            }
+            IdleRestartConf::Keep => {}
+        }
+        if self.idle_restart != IdleRestartConf::Keep {
+            self.velocity = 1;
+            self.cur_direction = 0;
+            self.prev_direction = 0;
+            self.time_since_direction = now;
+            self.prev_update_rtt = now;
+        }
+        self.rtt_win.age_out_idle(now);
+
+        if let Some(log) = self.logger.as_ref() {
+            info!(log, "idle restart";
+                "idle_time" => idle_time,
+                "policy" => ?self.idle_restart,
+                "prev_cwnd (pkts)" => prev_cwnd / 1448,
+                "curr_cwnd (pkts)" => self.cwnd / 1448,
+            );
+        }
+    }
+
+    // On shallow buffers packets are dropped before delay builds up, so delay
+    // control alone would keep the window where it is
+    fn handle_loss(&mut self, now: u64) {
+        if self.recovery.is_some()
+            || now < self.prev_loss_response + self.rtt_win.get_srtt() as u64
+        {
+            return;
+        }
+        let prev_cwnd = self.cwnd;
+        match self.loss_response {
+            LossResponseConf::None => return,
+            LossResponseConf::Decrease { beta } => {
+                self.cwnd = std::cmp::max(self.min_cwnd, (self.cwnd as f32 * beta) as u32);
+            }
+            LossResponseConf::Prr { beta } => {
+                self.recovery = Some(Recovery {
+                    recover_fs: self.cwnd,
+                    ssthresh: std::cmp::max(self.min_cwnd, (self.cwnd as f32 * beta) as u32),
+                    delivered: 0,
+                });
+            }
+        }
+        self.prev_loss_response = now;
+        self.slow_start = false;
+        self.velocity = 1;
+        self.time_since_direction = now;
+
+        if let Some(log) = self.logger.as_ref() {
+            debug!(log, "loss response";
+                "policy" => ?self.loss_response,
+                "prev_cwnd (pkts)" => prev_cwnd / 1448,
+                "curr_cwnd (pkts)" => self.cwnd / 1448,
+            );
+        }
+    }
+
+    // Shrink the window towards ssthresh as data is delivered, so that by the
+    // time a window's worth has been, what is in flight is ssthresh. Returns
+    // whether we were in recovery
+    fn recover(&mut self, acked: u32) -> bool {
+        let r = match self.recovery.as_mut() {
+            Some(r) => r,
+            None => return false,
+        };
+        r.delivered += acked;
+        if r.delivered >= r.recover_fs {
+            self.cwnd = r.ssthresh;
+            self.recovery = None;
+        } else {
+            let reduction =
+                r.delivered as u64 * (r.recover_fs - r.ssthresh) as u64 / r.recover_fs as u64;
+            self.cwnd = std::cmp::max(r.ssthresh, r.recover_fs - reduction as u32);
+        }
+        true
+    }
+
+    fn handle_timeout(&mut self) {
+        self.cwnd = std::cmp::max(self.init_cwnd, self.min_cwnd);
+        self.enter_slow_start();
+        self.recovery = None;
+
+        self.logger.as_ref().map(|log| {
+            warn!(log, "timeout";
+                "curr_cwnd (pkts)" => self.cwnd / 1448,
+            );
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RTT: u32 = 40_000;
+
+    fn controller(init_cwnd: u32) -> Controller {
+        let cfg = CopaConfig {
+            delta_mode: DeltaModeConf::NoTCP,
+            ..Default::default()
+        };
+        Controller::new(&cfg, init_cwnd, None)
+    }
+
+    fn report(ctl: &mut Controller, rtt: u32, now: u64) {
+        ctl.on_report(ReportStatus::Report, false, 10 * 1448, 0, 0, rtt, now);
+    }
+
+    #[test]
+    fn velocity_doubled_too_soon_is_reset() {
+        let mut ctl = controller(10 * 1448);
+        let mut now = 1_000_000;
+        report(&mut ctl, RTT, now);
+        // Increasing for a while, with velocity doubled when the RTT was
+        // smaller than it is now
+        ctl.slow_start = false;
+        ctl.velocity = 4;
+        ctl.prev_direction = 1;
+        ctl.time_since_direction = now - 2 * RTT as u64;
+        ctl.prev_update_rtt = now - 2 * RTT as u64;
+        now += RTT as u64 / 2;
+        report(&mut ctl, RTT, now);
+        assert_eq!(ctl.velocity, 1);
+        assert_eq!(ctl.num_violations, 0);
+    }
+
+    #[test]
+    #[cfg(not(feature = "strict-invariants"))]
+    fn inconsistent_state_resets_the_flow() {
+        let init_cwnd = 10 * 1448;
+        let mut ctl = controller(init_cwnd);
+        let mut now = 1_000_000;
+        for _ in 0..10 {
+            report(&mut ctl, RTT, now);
+            now += RTT as u64 / 2;
+        }
+
+        // A window below the minimum, that a queue keeps from growing
+        let violations = invariant::num_invariant_violations();
+        ctl.cwnd = ctl.min_cwnd / 2;
+        report(&mut ctl, 3 * RTT, now);
+        assert_eq!(ctl.num_violations, 1);
+        assert!(invariant::num_invariant_violations() > violations);
+        assert_eq!(ctl.cwnd, init_cwnd);
+        assert!(ctl.slow_start);
+
+        // And the flow carries on as a new one would
+        for _ in 0..10 {
+            now += RTT as u64 / 2;
+            report(&mut ctl, RTT, now);
+        }
+        assert!(ctl.cwnd > 2 * init_cwnd, "{}", ctl.cwnd);
+        assert_eq!(ctl.num_violations, 1);
+    }
+}
@@ -1,77 +1,182 @@
+use loss_estimator::LossEstimator;
 use rtt_window::RTTWindow;
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum DeltaModeConf {
     NoTCP,
     Auto,
+    /// Adapt delta to keep the queueing delay we see near `target` (us)
+    TargetDelay { target: u32 },
+    /// Yield to all other traffic, backing off whenever the queueing delay we
+    /// see exceeds `target` (us), as LEDBAT does
+    Scavenger { target: u32 },
 }
 
+// Delta never goes below this in target delay mode, however long the target
+const MIN_TARGET_DELTA: f32 = 0.001;
+// Range of delta in scavenger mode. Against a Copa flow with delta d we get
+// d/delta of its rate, so even at the bottom of the range we yield
+const MIN_SCAVENGER_DELTA: f32 = 16.;
+const MAX_SCAVENGER_DELTA: f32 = 256.;
+
+/// How delta is chosen at the moment
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum DeltaMode {
+    /// The configured default delta
     Default,
+    /// Competing with buffer-filling flows, so delta follows TCP's AIMD
     TCPCoop,
+    /// Competing with BBR, so delta follows AIMD on queueing delay
+    BBRCoop,
+    /// Delta follows the queueing delay toward the configured target
+    TargetDelay,
+    /// Delta is large, and grows further while the queueing delay is above
+    /// the configured target
+    Scavenger,
+    /// Losing packets to congestion, so delta follows `LossDeltaConf`
     Loss,
 }
 
+impl DeltaMode {
+    /// Every mode, in the order they are listed above
+    pub const ALL: [DeltaMode; 6] = [
+        DeltaMode::Default,
+        DeltaMode::TCPCoop,
+        DeltaMode::BBRCoop,
+        DeltaMode::TargetDelay,
+        DeltaMode::Scavenger,
+        DeltaMode::Loss,
+    ];
+
+    /// Short name, as in logs
+    pub fn name(&self) -> &'static str {
+        match self {
+            DeltaMode::Default => "const",
+            DeltaMode::TCPCoop => "tcp",
+            DeltaMode::BBRCoop => "bbr",
+            DeltaMode::TargetDelay => "target",
+            DeltaMode::Scavenger => "scavenger",
+            DeltaMode::Loss => "loss",
+        }
+    }
+}
+
+/// How delta moves in loss mode. It doubles on loss up to `max`, and comes
+/// down by `step` for every RTT without loss, down to `min`
+#[derive(Clone, Debug, PartialEq)]
+pub struct LossDeltaConf {
+    pub min: f32,
+    pub max: f32,
+    pub step: f32,
+}
+
+/// A change of delta mode
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModeTransition {
+    pub from: DeltaMode,
+    pub to: DeltaMode,
+    // Time of the report that caused it, and delta after it
+    pub now: u64,
+    pub delta: f32,
+}
+
 pub struct DeltaManager {
     // Configuration on how to choose delta
     switch_mode: DeltaModeConf,
     default_delta: f32,
-    // End of the last window of tracking losses
-    prev_loss_cycle: u64,
-    // Loss rate in the previous cycle
-    prev_loss_rate: f32,
-    // Number of acks and losses in current cycle
-    cur_num_acked: u32,
-    cur_num_losses: u32,
+    loss: LossEstimator,
     // Last time we reduced 1/delta due to loss, so we don't decrease twice
     // within the same RTT
     prev_loss_red_time: u64,
+    // In BBR mode, the last time we changed 1/delta
+    prev_bbr_step_time: u64,
+    loss_delta: LossDeltaConf,
+    // In loss mode, the last time we saw a loss or stepped delta down
+    prev_loss_step_time: u64,
+    // Average of min RTT less base RTT over recent reports (us)
+    queue_delay: f32,
+    // In target delay and scavenger modes, the last time we changed delta
+    prev_target_step_time: u64,
+    // Mode changes not yet taken by the owner
+    transitions: Vec<ModeTransition>,
     // Current state of delta
     cur_mode: DeltaMode,
     delta: f32,
 }
 
 impl DeltaManager {
-    pub fn new(default_delta: f32, mode: DeltaModeConf) -> Self {
+    pub fn new(default_delta: f32, mode: DeltaModeConf, loss_delta: LossDeltaConf) -> Self {
         let cur_mode = match mode {
             DeltaModeConf::NoTCP => DeltaMode::Default,
             DeltaModeConf::Auto => DeltaMode::TCPCoop,
+            DeltaModeConf::TargetDelay { .. } => DeltaMode::TargetDelay,
+            DeltaModeConf::Scavenger { .. } => DeltaMode::Scavenger,
         };
         if default_delta > 1.0 {
             panic!("Default delta should be less than or equal to 1.");
         }
+        if loss_delta.min <= 0. || loss_delta.min > loss_delta.max || loss_delta.step <= 0. {
+            panic!("Loss mode delta should have 0 < min <= max and a positive step.");
+        }
+        if mode == (DeltaModeConf::TargetDelay { target: 0 })
+            || mode == (DeltaModeConf::Scavenger { target: 0 })
+        {
+            panic!("Target queueing delay should be positive.");
+        }
+        let delta = match mode {
+            DeltaModeConf::Scavenger { .. } => MIN_SCAVENGER_DELTA,
+            _ => 0.5,
+        };
         Self {
             switch_mode: mode,
             default_delta: default_delta,
-            prev_loss_cycle: 0,
-            prev_loss_rate: 0.,
-            cur_num_acked: 0,
-            cur_num_losses: 0,
+            loss: LossEstimator::new(),
             prev_loss_red_time: 0,
+            prev_bbr_step_time: 0,
+            loss_delta,
+            prev_loss_step_time: 0,
+            queue_delay: 0.,
+            prev_target_step_time: 0,
+            transitions: Vec::new(),
             cur_mode: cur_mode,
-            delta: 0.5,
+            delta,
         }
     }
 
-    pub fn report_measurement(&mut self, rtt_win: &mut RTTWindow, acked: u32, lost: u32, now: u64) {
+    pub fn report_measurement(
+        &mut self,
+        rtt_win: &mut RTTWindow,
+        acked: u32,
+        lost: u32,
+        min_rtt: u32,
+        now: u64,
+    ) {
         // Update loss rate estimate
-        self.cur_num_acked += acked;
-        self.cur_num_losses += lost;
-        if now > self.prev_loss_cycle + 2 * rtt_win.get_base_rtt() as u64 {
-            self.prev_loss_cycle = now;
-            if self.cur_num_losses + self.cur_num_acked > 0 {
-                self.prev_loss_rate =
-                    self.cur_num_losses as f32 / (self.cur_num_losses + self.cur_num_acked) as f32;
-            }
-            self.cur_num_acked = 0;
-            self.cur_num_losses = 0;
+        self.loss.report(rtt_win, acked, lost, min_rtt, now);
+
+        let base_rtt = rtt_win.get_base_rtt();
+        if min_rtt != 0 && min_rtt != u32::MAX && base_rtt != u32::MAX {
+            let sample = min_rtt.saturating_sub(base_rtt) as f32;
+            self.queue_delay += (sample - self.queue_delay) / 8.;
         }
 
+        let prev_mode = self.cur_mode.clone();
+
         // Set delta mode
-        // If we are losing more than 10% of packets, move to loss mode. Period.
-        if self.prev_loss_rate >= 0.1 {
+        // A scavenger backs off on its own, further than loss mode would
+        // If we are losing more than 10% of packets to congestion, move to loss
+        // mode. Period. Random loss says nothing about how much to back off.
+        // Once there, stay till delta has come all the way back down
+        if let DeltaModeConf::Scavenger { .. } = self.switch_mode {
+            self.cur_mode = DeltaMode::Scavenger;
+        } else if self.loss.get_congestive_loss_rate() >= 0.1
+            || (prev_mode == DeltaMode::Loss && self.delta > self.loss_delta.min)
+        {
             self.cur_mode = DeltaMode::Loss;
+        } else if let DeltaModeConf::TargetDelay { .. } = self.switch_mode {
+            self.cur_mode = DeltaMode::TargetDelay;
+        } else if self.switch_mode == DeltaModeConf::Auto && rtt_win.bbr_detected() {
+            self.cur_mode = DeltaMode::BBRCoop;
         } else {
             // See if we need to be in TCP mode
             if self.switch_mode == DeltaModeConf::Auto
@@ -103,21 +208,114 @@ impl DeltaManager {
                     self.delta = self.default_delta;
                 }
             }
+            DeltaMode::BBRCoop => {
+                // BBR keeps up to two BDPs in flight, so it holds RTT near
+                // twice the base RTT however the bandwidth is split, and
+                // yields to none of our delay-based backing off. Push a little
+                // harder every RTT. Only once the queue is twice as long, such
+                // that BBR can't keep up its rate, back off
+                if now >= self.prev_bbr_step_time + rtt_win.get_srtt() as u64 {
+                    if rtt_win.get_latest_rtt() as u64 >= 3 * rtt_win.get_base_rtt() as u64 {
+                        self.delta *= 2.;
+                    } else {
+                        self.delta = 1. / (1. + 1. / self.delta);
+                    }
+                    self.prev_bbr_step_time = now;
+                }
+                if self.delta > self.default_delta {
+                    self.delta = self.default_delta;
+                }
+            }
+            DeltaMode::TargetDelay => {
+                // Copa's queueing delay is inversely proportional to delta.
+                // Once per RTT, scale delta by the ratio of the queueing delay
+                // to the target, damped and within bounds so that noise in
+                // the one and an empty queue don't throw it about
+                if now >= self.prev_target_step_time + rtt_win.get_srtt() as u64 {
+                    let ratio = (self.queue_delay / self.get_target_delay().unwrap() as f32)
+                        .clamp(0.5, 2.);
+                    self.delta = (self.delta * ratio.sqrt()).clamp(MIN_TARGET_DELTA, 1.);
+                    self.prev_target_step_time = now;
+                }
+            }
+            DeltaMode::Scavenger => {
+                // Back off hard as soon as there is more of a queue than we
+                // are willing to add to, or loss, and come back slowly
+                if now >= self.prev_target_step_time + rtt_win.get_srtt() as u64 {
+                    if lost > 0 || self.queue_delay > self.get_target_delay().unwrap() as f32 {
+                        self.delta *= 2.;
+                    } else {
+                        self.delta *= 0.875;
+                    }
+                    self.delta = self.delta.clamp(MIN_SCAVENGER_DELTA, MAX_SCAVENGER_DELTA);
+                    self.prev_target_step_time = now;
+                }
+            }
             DeltaMode::Loss => {
+                // Back off on loss, and recover once losses stop, one step per
+                // lossless RTT
+                if prev_mode != DeltaMode::Loss {
+                    self.prev_loss_step_time = now;
+                }
                 if lost > 0 {
                     self.delta *= 2.;
+                    self.prev_loss_step_time = now;
+                } else if now >= self.prev_loss_step_time + rtt_win.get_srtt() as u64 {
+                    self.delta -= self.loss_delta.step;
+                    self.prev_loss_step_time = now;
                 }
-                if self.delta >= self.default_delta {
-                    self.delta = self.default_delta;
-                }
+                self.delta = self.delta.max(self.loss_delta.min).min(self.loss_delta.max);
             }
         };
+
+        if self.cur_mode != prev_mode {
+            self.transitions.push(ModeTransition {
+                from: prev_mode,
+                to: self.cur_mode.clone(),
+                now,
+                delta: self.delta,
+            });
+        }
+    }
+
+    // Start afresh with the same configuration
+    pub fn reset(&mut self) {
+        *self = Self::new(
+            self.default_delta,
+            self.switch_mode.clone(),
+            self.loss_delta.clone(),
+        );
+    }
+
+    pub fn take_transitions(&mut self) -> Vec<ModeTransition> {
+        std::mem::take(&mut self.transitions)
     }
 
     pub fn get_delta(&self) -> f32 {
         self.delta
     }
 
+    pub fn get_loss_rate(&self) -> f32 {
+        self.loss.get_loss_rate()
+    }
+
+    pub fn get_congestive_loss_rate(&self) -> f32 {
+        self.loss.get_congestive_loss_rate()
+    }
+
+    pub fn get_queue_delay(&self) -> u32 {
+        self.queue_delay as u32
+    }
+
+    pub fn get_target_delay(&self) -> Option<u32> {
+        match self.switch_mode {
+            DeltaModeConf::TargetDelay { target } | DeltaModeConf::Scavenger { target } => {
+                Some(target)
+            }
+            _ => None,
+        }
+    }
+
     pub fn get_mode(&self) -> DeltaMode {
         self.cur_mode.clone()
     }
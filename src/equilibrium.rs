@@ -0,0 +1,53 @@
+//! Where Copa flows sharing a bottleneck settle, per the analysis in the Copa
+//! paper, so that delta choices can be sanity-checked before they are rolled
+//! out.
+//!
+//! A flow with delta d aims for a rate of 1/(d * dq) packets per second, where
+//! dq is the queueing delay it sees. With every flow at its target and the
+//! link busy, the rates sum to the link rate C, so dq = sum(1/d) / C, i.e. the
+//! flows keep sum(1/d) packets queued, and a flow's rate is in proportion to
+//! its 1/d. Windows don't sit still at the target but swing about it, moving
+//! by 1/d packets every RTT with a period of a few RTTs. Since all flows see
+//! the same queue, they swing together, and the queue swings by about
+//! 2.5 * sum(1/d) packets from peak to trough.
+
+/// The equilibrium of Copa flows on a FIFO bottleneck
+#[derive(Clone, Debug, PartialEq)]
+pub struct Equilibrium {
+    /// Rate of each flow, in bytes per second
+    pub rates: Vec<f64>,
+    /// Queueing delay the flows aim to hold the queue at, in us. This is the
+    /// queue at its shortest over an RTT, which is what Copa measures. The
+    /// queue on average is longer, by up to half of `amplitude`
+    pub queueing_delay: f64,
+    /// Peak to trough swing of the queueing delay, in us
+    pub amplitude: f64,
+    /// Whether some flow would need a window below `min_cwnd`. The flows
+    /// then hold more in the queue than the analysis says
+    pub window_limited: bool,
+}
+
+impl Equilibrium {
+    /// `link_rate` is in bytes per second, `prop_rtt` in us, `deltas` are
+    /// those of each flow (with weights applied) and `min_cwnd` the smallest
+    /// window flows go down to, in bytes
+    pub fn new(link_rate: f64, prop_rtt: u64, mss: u32, deltas: &[f32], min_cwnd: u32) -> Self {
+        if link_rate <= 0. || deltas.iter().any(|&d| d <= 0.) {
+            panic!("Link rate and deltas should be positive.");
+        }
+        let inv_deltas: Vec<f64> = deltas.iter().map(|&d| 1. / d as f64).collect();
+        let sum: f64 = inv_deltas.iter().sum();
+        // Time to send one packet
+        let pkt_time = mss as f64 * 1e6 / link_rate;
+        let queueing_delay = sum * pkt_time;
+        let rates: Vec<f64> = inv_deltas.iter().map(|d| link_rate * d / sum).collect();
+        let rtt = prop_rtt as f64 + queueing_delay;
+        let window_limited = rates.iter().any(|r| r * rtt / 1e6 < min_cwnd as f64);
+        Self {
+            rates,
+            queueing_delay,
+            amplitude: 2.5 * sum * pkt_time,
+            window_limited,
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use delta_manager::DeltaMode;
+
+// A storm is more than these many delta mode changes within the period (us)
+const MODE_FLIP_STORM_COUNT: usize = 5;
+const MODE_FLIP_STORM_PERIOD: u64 = 1_000_000;
+
+#[derive(Clone, Debug)]
+pub struct FlightRecorderConf {
+    // Number of recent reports to keep per flow
+    pub capacity: usize,
+    // Dumps go to a file in this directory if set, and to the log otherwise
+    pub dump_dir: Option<PathBuf>,
+}
+
+// What a flow saw and decided on one report
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub now: u64,
+    pub acked: u32,
+    pub loss: u32,
+    pub min_rtt: u32,
+    pub base_rtt: u32,
+    pub cwnd: u32,
+    pub delta: f32,
+    pub mode: DeltaMode,
+    pub velocity: u32,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Anomaly {
+    Timeout,
+    InvariantViolation,
+    ModeFlipStorm,
+    Panic,
+}
+
+// Keeps the recent history of a flow, to be dumped when something goes wrong
+pub struct FlightRecorder {
+    conf: FlightRecorderConf,
+    sock_id: u32,
+    entries: VecDeque<Entry>,
+    // Times at which the delta mode changed, within the storm period
+    mode_flips: VecDeque<u64>,
+}
+
+impl FlightRecorder {
+    pub fn new(conf: FlightRecorderConf, sock_id: u32) -> Self {
+        Self {
+            entries: VecDeque::with_capacity(conf.capacity),
+            conf,
+            sock_id,
+            mode_flips: VecDeque::new(),
+        }
+    }
+
+    // Record an entry. Returns an anomaly if the delta mode is flipping too
+    // often
+    pub fn record(&mut self, e: Entry) -> Option<Anomaly> {
+        let flipped = self.entries.back().is_some_and(|prev| prev.mode != e.mode);
+        if flipped {
+            self.mode_flips.push_back(e.now);
+        }
+        while self
+            .mode_flips
+            .front()
+            .is_some_and(|t| t + MODE_FLIP_STORM_PERIOD < e.now)
+        {
+            self.mode_flips.pop_front();
+        }
+
+        if self.entries.len() >= self.conf.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(e);
+
+        if self.mode_flips.len() > MODE_FLIP_STORM_COUNT {
+            self.mode_flips.clear();
+            return Some(Anomaly::ModeFlipStorm);
+        }
+        None
+    }
+
+    // Dump what we have and start afresh, so that the same history is not
+    // dumped twice
+    pub fn dump(&mut self, anomaly: Anomaly, logger: Option<&slog::Logger>) {
+        let entries: Vec<Entry> = self.entries.drain(..).collect();
+        let now = entries.last().map_or(0, |e| e.now);
+
+        if let Some(dir) = self.conf.dump_dir.as_ref() {
+            let path = dir.join(format!("copa-flight-{}-{}.txt", self.sock_id, now));
+            let res = File::create(&path).and_then(|f| {
+                let mut w = BufWriter::new(f);
+                writeln!(w, "sock_id {} anomaly {:?}", self.sock_id, anomaly)?;
+                for e in entries.iter() {
+                    writeln!(w, "{:?}", e)?;
+                }
+                w.flush()
+            });
+            if let Some(log) = logger {
+                match res {
+                    Ok(()) => warn!(log, "flight recorder dumped";
+                        "sock_id" => self.sock_id,
+                        "anomaly" => ?anomaly,
+                        "path" => ?path,
+                    ),
+                    Err(e) => warn!(log, "flight recorder dump failed";
+                        "sock_id" => self.sock_id,
+                        "anomaly" => ?anomaly,
+                        "err" => ?e,
+                    ),
+                }
+            }
+            return;
+        }
+
+        if let Some(log) = logger {
+            warn!(log, "flight recorder dump";
+                "sock_id" => self.sock_id,
+                "anomaly" => ?anomaly,
+                "num_entries" => entries.len(),
+            );
+            for e in entries.iter() {
+                warn!(log, "flight recorder";
+                    "sock_id" => self.sock_id,
+                    "now" => e.now,
+                    "acked" => e.acked,
+                    "loss" => e.loss,
+                    "min_rtt" => e.min_rtt,
+                    "base_rtt" => e.base_rtt,
+                    "cwnd" => e.cwnd,
+                    "delta" => e.delta,
+                    "mode" => ?e.mode,
+                    "velocity" => e.velocity,
+                );
+            }
+        }
+    }
+}
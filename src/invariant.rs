@@ -0,0 +1,22 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static NUM_VIOLATIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of invariant violations seen across all flows so far
+pub fn num_invariant_violations() -> usize {
+    NUM_VIOLATIONS.load(Ordering::Relaxed)
+}
+
+// Returns whether the invariant holds. Violations are counted, and the caller
+// is expected to recover. Built with the `strict-invariants` feature, this
+// panics instead, like assert! would
+pub fn check(holds: bool, what: &str) -> bool {
+    if holds {
+        return true;
+    }
+    NUM_VIOLATIONS.fetch_add(1, Ordering::Relaxed);
+    if cfg!(feature = "strict-invariants") {
+        panic!("invariant violated: {}", what);
+    }
+    false
+}
@@ -1,5 +1,16 @@
+// Much of the code predates these lints, and is kept as it was written
+#![allow(
+    clippy::legacy_numeric_constants,
+    clippy::len_zero,
+    clippy::needless_return,
+    clippy::option_map_unit_fn,
+    clippy::redundant_field_names,
+    clippy::unnecessary_cast
+)]
+
 extern crate clap;
 use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 
 #[macro_use]
 extern crate slog;
@@ -11,28 +22,87 @@ use portus::{CongAlg, Datapath, DatapathInfo, DatapathTrait, Report};
 
 mod delta_manager;
 mod rtt_window;
-pub use delta_manager::DeltaModeConf;
-use delta_manager::{DeltaManager, DeltaMode};
-use rtt_window::RTTWindow;
+mod loss_estimator;
+pub use delta_manager::{DeltaMode, DeltaModeConf, LossDeltaConf, ModeTransition};
 mod agg_measurement;
-use agg_measurement::{AggMeasurement, ReportStatus};
+pub use agg_measurement::Measurement;
+use agg_measurement::AggMeasurement;
+mod path_cache;
+pub use path_cache::PathCache;
+mod controller;
+use controller::{Controller, SharedController};
+mod aggregate;
+pub use aggregate::Aggregator;
+mod multipath;
+pub use multipath::MultipathGroups;
+pub mod recorder;
+use recorder::{FlowState, Recorder};
+pub mod analyze;
+mod invariant;
+pub use invariant::num_invariant_violations;
+mod slow_start;
+pub use slow_start::SlowStartConf;
+mod probe_rtt;
+pub use probe_rtt::ProbeRttConf;
+use probe_rtt::ProbeRtt;
+mod flight_recorder;
+pub use flight_recorder::FlightRecorderConf;
+use flight_recorder::{Anomaly, Entry, FlightRecorder};
+mod policy;
+pub use policy::{FlowMatch, FlowPolicy, PolicyTable};
+mod equilibrium;
+pub use equilibrium::Equilibrium;
+// For the tests and tools in this crate, rather than part of its API
+#[doc(hidden)]
+pub mod sim;
 
-pub struct Copa<T: Ipc> {
-    control_channel: Datapath<T>,
+pub struct Copa<D: DatapathTrait> {
+    control_channel: D,
     logger: Option<slog::Logger>,
     sc: Scope,
-    delta_manager: DeltaManager,
     prev_report_time: u64,
-    cwnd: u32,
-    init_cwnd: u32,
-    slow_start: bool,
-    rtt_win: RTTWindow,
-    velocity: u32,
-    cur_direction: i64,
-    prev_direction: i64,
-    time_since_direction: u64,
-    prev_update_rtt: u64,
     agg_measurement: AggMeasurement,
+    path_cache: Option<PathCache>,
+    path_key: u32,
+    // Shared with the other flows of our aggregate, if any
+    ctl: SharedController,
+    sock_id: u32,
+    recorder: Option<Recorder>,
+    flight_recorder: Option<FlightRecorder>,
+    // Invariant violations of the controller we have already dumped for
+    num_violations: u32,
+    // Base RTT we last wrote to the datapath program's `basertt`
+    datapath_base_rtt: u32,
+    probe_rtt: Option<ProbeRtt>,
+    policies: Option<PolicyTable>,
+    info: DatapathInfo,
+}
+
+// Pacing rate never goes below this (bytes/s), unless a flow policy says so
+const MIN_RATE: u32 = 2_000;
+
+/// What to do with the window when a flow resumes after being idle for longer
+/// than a retransmission timeout
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum IdleRestartConf {
+    /// Restart from the initial window in slow start
+    Reset,
+    /// Halve the window for every RTO spent idle, as in RFC 2861
+    Decay,
+    /// Resume with the window from before the idle period
+    Keep,
+}
+
+/// How the window responds to loss, besides the change in delta
+#[derive(Clone, Debug, PartialEq)]
+pub enum LossResponseConf {
+    /// Leave the window to delay control
+    None,
+    /// Multiply the window by `beta`, at most once per RTT
+    Decrease { beta: f32 },
+    /// Bring the window down to `beta` times its size over the next round
+    /// trip, in proportion to what is delivered, as in PRR (RFC 6937)
+    Prr { beta: f32 },
 }
 
 #[derive(Clone)]
@@ -41,260 +111,421 @@ pub struct CopaConfig {
     pub init_cwnd: u32,
     pub default_delta: f32,
     pub delta_mode: DeltaModeConf,
+    pub loss_delta: LossDeltaConf,
+    pub idle_restart: IdleRestartConf,
+    pub slow_start: SlowStartConf,
+    pub loss_response: LossResponseConf,
+    // Base RTT is the minimum RTT over at least this long (us). It is longer
+    // on paths where 30 RTTs take longer
+    pub rtt_history: u64,
+    // If set, base RTT is re-estimated as soon as the path is seen to have
+    // gotten longer, rather than once older samples age out
+    pub detect_route_changes: bool,
+    pub path_cache: Option<PathCache>,
+    // Relative share of a bottleneck this flow should get compared to other
+    // Copa flows. Copa's rate is ~1/(delta * queueing delay), so we scale delta
+    // by 1/weight
+    pub weight: f32,
+    // If set, flows to the same destination share a single controller
+    pub aggregator: Option<Aggregator>,
+    // If set, subflows of a multipath connection are coupled
+    pub multipath: Option<MultipathGroups>,
+    // If set, what every flow sees and decides is recorded for replay
+    pub recorder: Option<Recorder>,
+    // If set, recent history of each flow is kept and dumped on anomalies
+    pub flight_recorder: Option<FlightRecorderConf>,
+    // If set, flows periodically drain the queue to measure base RTT afresh
+    pub probe_rtt: Option<ProbeRttConf>,
+    // If set, flows are held to the rate and window bounds of their policy
+    pub policies: Option<PolicyTable>,
+}
+
+// The same defaults as the copa binary's
+impl Default for CopaConfig {
+    fn default() -> Self {
+        Self {
+            logger: None,
+            init_cwnd: 0,
+            default_delta: 0.5,
+            delta_mode: DeltaModeConf::Auto,
+            loss_delta: LossDeltaConf {
+                min: 0.5,
+                max: 1.,
+                step: 0.05,
+            },
+            idle_restart: IdleRestartConf::Decay,
+            slow_start: SlowStartConf::Classic,
+            loss_response: LossResponseConf::None,
+            rtt_history: 10_000_000,
+            detect_route_changes: false,
+            path_cache: None,
+            weight: 1.,
+            aggregator: None,
+            multipath: None,
+            recorder: None,
+            flight_recorder: None,
+            probe_rtt: None,
+            policies: None,
+        }
+    }
 }
 
-impl<T: Ipc> Copa<T> {
-    fn compute_rate(&self) -> u32 {
-        (2 * self.cwnd as u64 * 1_000_000 / self.rtt_win.get_base_rtt() as u64) as u32
+impl<D: DatapathTrait> Copa<D> {
+    pub fn new(cfg: &CopaConfig, control: D, info: DatapathInfo) -> Self {
+        let init_cwnd = match cfg.init_cwnd {
+            0 => info.init_cwnd,
+            init_cwnd => init_cwnd,
+        };
+        let new_controller = || {
+            let subflow = cfg.multipath.as_ref().map(|groups| groups.join(&info));
+            Controller::new(cfg, init_cwnd, subflow)
+        };
+        let (ctl, new_ctl) = match cfg.aggregator.as_ref() {
+            Some(aggregator) => aggregator.join(&info, new_controller),
+            None => (Arc::new(Mutex::new(new_controller())), true),
+        };
+        ctl.lock().unwrap().add_flow(info.sock_id);
+        let mut s = Copa {
+            control_channel: control,
+            logger: cfg.logger.clone(),
+            sc: Default::default(),
+            agg_measurement: AggMeasurement::new(0.5),
+            prev_report_time: 0,
+            path_cache: cfg.path_cache.clone(),
+            path_key: 0,
+            ctl,
+            sock_id: info.sock_id,
+            recorder: cfg.recorder.clone(),
+            flight_recorder: cfg
+                .flight_recorder
+                .clone()
+                .map(|conf| FlightRecorder::new(conf, info.sock_id)),
+            num_violations: 0,
+            datapath_base_rtt: u32::MAX,
+            probe_rtt: cfg.probe_rtt.clone().map(ProbeRtt::new),
+            policies: cfg.policies.clone(),
+            info: info.clone(),
+        };
+        if let Some(recorder) = s.recorder.as_ref() {
+            recorder.flow(&info);
+        }
+
+        // Start from what recent flows on the same path learnt. Use only half
+        // their window, since it may have been a share of a busier bottleneck
+        if let Some(cache) = cfg.path_cache.as_ref() {
+            s.path_key = cache.key(info.dst_ip);
+            match cache.lookup(s.path_key) {
+                Some((base_rtt, cwnd)) if new_ctl => {
+                    s.ctl.lock().unwrap().seed(base_rtt, cwnd / 2);
+                    if let Some(log) = cfg.logger.as_ref() {
+                        info!(log, "seeded from path cache";
+                            "sock_id" => info.sock_id,
+                            "base_rtt" => base_rtt,
+                            "curr_cwnd (pkts)" => s.ctl.lock().unwrap().get_cwnd() / 1448,
+                        );
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        cfg.logger.as_ref().map(|log| {
+            info!(log, "starting copa flow";
+                "sock_id" => info.sock_id,
+                "aggregate_size" => Arc::strong_count(&s.ctl),
+            );
+        });
+
+        s.sc = s.control_channel.set_program("copa", None).unwrap();
+        s.update();
+        s
+    }
+
+    // All updates to the datapath go through here, so that they are recorded
+    fn send(&self, update: &[(&str, u32)]) {
+        if let Some(recorder) = self.recorder.as_ref() {
+            recorder.update(self.sock_id, update);
+        }
+        self.control_channel.update_field(&self.sc, update).unwrap();
+    }
+
+    fn policy(&self) -> FlowPolicy {
+        self.policies
+            .as_ref()
+            .and_then(|policies| policies.lookup(&self.info))
+            .unwrap_or_default()
+    }
+
+    // Largest window the policy allows. A max rate allows enough window to
+    // keep it up with as much queueing delay as base RTT
+    fn policy_max_cwnd(policy: &FlowPolicy, base_rtt: u32) -> Option<u32> {
+        let rate_cwnd = match (policy.max_rate, base_rtt) {
+            (_, u32::MAX) | (None, _) => None,
+            (Some(rate), base_rtt) => {
+                Some((2 * rate as u64 * base_rtt as u64 / 1_000_000) as u32)
+            }
+        };
+        match (policy.max_cwnd, rate_cwnd) {
+            (Some(a), Some(b)) => Some(std::cmp::min(a, b)),
+            (a, b) => a.or(b),
+        }
     }
 
     fn update(&self) {
-        let rate = std::cmp::max(self.compute_rate(), 2_000);
+        let ctl = self.ctl.lock().unwrap();
+        let policy = self.policy();
+        let mut cwnd = ctl.share(self.sock_id);
+        // A min rate needs enough window to keep it up with as much queueing
+        // delay as base RTT. Caps win over it
+        if let (Some(rate), true) = (policy.min_rate, ctl.get_base_rtt() != u32::MAX) {
+            let min_cwnd = 2 * rate as u64 * ctl.get_base_rtt() as u64 / 1_000_000;
+            cwnd = std::cmp::max(cwnd, min_cwnd as u32);
+        }
+        if let Some(max_cwnd) = Self::policy_max_cwnd(&policy, ctl.get_base_rtt()) {
+            cwnd = std::cmp::min(cwnd, max_cwnd);
+        }
+        if let Some(probe_rtt) = self.probe_rtt.as_ref().filter(|p| p.is_probing()) {
+            cwnd = std::cmp::min(cwnd, probe_rtt.get_cwnd());
+        }
+        if ctl.bbr_probe_rtt_due() {
+            cwnd = std::cmp::min(cwnd, 4 * 1448);
+        }
+
+        // Without an RTT sample there is no meaningful rate, so leave pacing
+        // to the datapath till then
+        if ctl.get_base_rtt() == u32::MAX {
+            self.send(&[("Cwnd", cwnd)]);
+            return;
+        }
+
+        let mut rate = std::cmp::max(
+            (2 * cwnd as u64 * 1_000_000 / ctl.get_base_rtt() as u64) as u32,
+            policy.min_rate.unwrap_or(MIN_RATE),
+        );
+        if let Some(max_rate) = policy.max_rate {
+            rate = std::cmp::min(rate, max_rate);
+        }
         self.logger.as_ref().map(|log| {
             debug!(log, "update";
-                "curr_cwnd (pkts)" => self.cwnd / 1460,
+                "curr_cwnd (pkts)" => cwnd / 1460,
                 "rate" => rate,
+                "policy" => ?policy,
             );
         });
 
-        self.control_channel
-            .update_field(&self.sc, &[("Cwnd", self.cwnd), ("Rate", rate)])
-            .unwrap()
+        self.send(&[("Cwnd", cwnd), ("Rate", rate)]);
     }
 
-    fn delay_control(&mut self, rtt: u32, actual_acked: u32, now: u64) {
-        let increase = rtt as u64 * 1460u64
-            > (((rtt - self.rtt_win.get_base_rtt()) as f64)
-                * self.delta_manager.get_delta() as f64
-                * self.cwnd as f64) as u64;
-
-        let mut acked = actual_acked;
-        // Just in case. Sometimes CCP returns after significantly longer than
-        // what was asked for. In that case, actual_acked can be huge
-        if actual_acked > self.cwnd {
-            acked = self.cwnd;
-        }
-        // Update velocity
-        if increase {
-            self.cur_direction += 1;
-        } else {
-            self.cur_direction -= 1;
-        }
+    /// Whether the flow is still in slow start
+    pub fn in_slow_start(&self) -> bool {
+        self.ctl.lock().unwrap().in_slow_start()
+    }
 
-        if self.velocity > 1
-            && ((increase && self.prev_direction < 0) || (!increase && self.prev_direction > 0))
-        {
-            self.velocity = 1;
-            self.time_since_direction = now;
-        }
+    /// Number of times the path was found to have changed to a longer one
+    pub fn num_route_changes(&self) -> u32 {
+        self.ctl.lock().unwrap().get_num_route_changes()
+    }
 
-        if now - self.prev_update_rtt >= 2 * rtt as u64 && !self.slow_start {
-            // TODO(venkatar): Time (now) may be a u32 internally, which means it
-            // will wrap around. Handle this.
-            if (self.prev_direction > 0 && self.cur_direction > 0)
-                || (self.prev_direction < 0 && self.cur_direction < 0)
-            {
-                if (now - self.time_since_direction) as u32 > 3 * rtt {
-                    self.velocity *= 2;
-                } else {
-                    assert!(self.velocity == 1);
+    /// Act on a report from the datapath program. Returns the changes of delta
+    /// mode it caused
+    pub fn on_measurement(&mut self, m: Measurement) -> Vec<ModeTransition> {
+        if let Some(recorder) = self.recorder.as_ref() {
+            recorder.measurement(self.sock_id, &m);
+        }
+        let (report_status, was_timeout, acked, sacked, loss, _inflight, _rtt, min_rtt, now) =
+            self.agg_measurement.report(m);
+        let transitions = {
+            let mut ctl = self.ctl.lock().unwrap();
+            ctl.on_report(report_status, was_timeout, acked, sacked, loss, min_rtt, now);
+            // Every flow sharing the controller gets a part of its window
+            if let Some(max_cwnd) = Self::policy_max_cwnd(&self.policy(), ctl.get_base_rtt()) {
+                ctl.limit_cwnd(max_cwnd * Arc::strong_count(&self.ctl) as u32);
+            }
+            // The datapath only ever lowers `basertt` on its own, so it has to
+            // be told when old samples age out and the base RTT goes up. It
+            // sets the reporting interval
+            let base_rtt = ctl.get_base_rtt();
+            if base_rtt != u32::MAX && base_rtt != self.datapath_base_rtt {
+                self.datapath_base_rtt = base_rtt;
+                self.send(&[("basertt", base_rtt)]);
+            }
+            if let Some(cache) = self.path_cache.as_ref() {
+                if ctl.get_base_rtt() != u32::MAX {
+                    cache.record(self.path_key, ctl.get_base_rtt(), ctl.get_cwnd());
                 }
-            } else {
-                self.velocity = 1;
-                self.time_since_direction = now;
             }
-            if self.velocity > 0xffff {
-                self.velocity = 0xffff;
+            ctl.take_mode_transitions()
+        };
+        if let Some(log) = self.logger.as_ref() {
+            for t in transitions.iter() {
+                info!(log, "delta mode";
+                    "sock_id" => self.sock_id,
+                    "from" => ?t.from,
+                    "to" => ?t.to,
+                    "delta" => t.delta,
+                );
             }
-            self.prev_direction = self.cur_direction;
-            self.cur_direction = 0;
-            self.prev_update_rtt = now;
         }
 
-        // Change window
-        if self.slow_start {
-            if increase {
-                self.cwnd += acked;
-            } else {
-                self.slow_start = false;
+        if let Some(probe_rtt) = self.probe_rtt.as_mut() {
+            let was_probing = probe_rtt.is_probing();
+            probe_rtt.on_report(now, self.ctl.lock().unwrap().get_base_rtt());
+            let mut ctl = self.ctl.lock().unwrap();
+            ctl.set_probing(probe_rtt.is_probing());
+            if was_probing || probe_rtt.is_probing() {
+                // The queue we drain is not a BBR competitor's doing
+                ctl.note_own_probe_rtt(now);
             }
-        } else {
-            let mut velocity = 1u64;
-            if (increase && self.prev_direction > 0) || (!increase && self.prev_direction < 0) {
-                velocity = self.velocity as u64;
+            if probe_rtt.is_probing() != was_probing {
+                if let Some(log) = self.logger.as_ref() {
+                    info!(log, "probe rtt";
+                        "sock_id" => self.sock_id,
+                        "probing" => probe_rtt.is_probing(),
+                        "base_rtt" => ctl.get_base_rtt(),
+                    );
+                }
             }
+        }
 
-            // If we are in TCP mode, delta changes with time. Account for that.
-            let delta = match !increase && self.delta_manager.get_mode() == DeltaMode::TCPCoop {
-                false => self.delta_manager.get_delta(),
-                true => 1. / (1. + 1. / self.delta_manager.get_delta()),
-            };
+        // Send decisions to CCP
+        self.update();
 
-            // Do computations in u64 to avoid overflow. Multiply first so
-            // integer division doesn't cause as many problems
-            let change =
-                (velocity * 1448 * (acked as u64) / (self.cwnd as f32 * delta) as u64) as u32;
+        if let Some(recorder) = self.recorder.as_ref() {
+            let ctl = self.ctl.lock().unwrap();
+            recorder.state(
+                self.sock_id,
+                &FlowState {
+                    mode: ctl.get_mode(),
+                    delta: ctl.get_delta(),
+                    velocity: ctl.get_velocity(),
+                    slow_start: ctl.in_slow_start(),
+                    base_rtt: ctl.get_base_rtt(),
+                    cwnd: ctl.get_cwnd(),
+                },
+            );
+        }
 
-            if increase {
-                self.cwnd += change;
+        if let Some(flight_recorder) = self.flight_recorder.as_mut() {
+            let ctl = self.ctl.lock().unwrap();
+            let storm = flight_recorder.record(Entry {
+                now,
+                acked,
+                loss,
+                min_rtt,
+                base_rtt: ctl.get_base_rtt(),
+                cwnd: ctl.get_cwnd(),
+                delta: ctl.get_delta(),
+                mode: ctl.get_mode(),
+                velocity: ctl.get_velocity(),
+            });
+            let anomaly = if was_timeout {
+                Some(Anomaly::Timeout)
+            } else if ctl.get_num_violations() > self.num_violations {
+                self.num_violations = ctl.get_num_violations();
+                Some(Anomaly::InvariantViolation)
             } else {
-                if change + self.init_cwnd > self.cwnd {
-                    self.cwnd = self.init_cwnd;
-                    self.velocity = 1;
-                    self.time_since_direction = now;
-                } else {
-                    self.cwnd -= change;
-                }
+                storm
+            };
+            if let Some(anomaly) = anomaly {
+                flight_recorder.dump(anomaly, self.logger.as_ref());
             }
         }
-        assert!(self.cwnd >= self.init_cwnd);
-    }
-
-    fn handle_timeout(&mut self) {
-        self.cwnd = self.init_cwnd;
-        self.slow_start = true;
 
         self.logger.as_ref().map(|log| {
-            warn!(log, "timeout";
-                "curr_cwnd (pkts)" => self.cwnd / 1448,
+            let ctl = self.ctl.lock().unwrap();
+            info!(log, "got ack";
+                   "acked(pkts)" => acked / 1448u32,
+                   "curr_cwnd (pkts)" => ctl.share(self.sock_id) / 1460,
+                   "loss" => loss,
+                   "sacked" => sacked,
+                   "delta" => ctl.get_delta(),
+                   "min_rtt" => min_rtt,
+                   "base_rtt" => ctl.get_base_rtt(),
+                   "queue_delay" => ctl.get_queue_delay(),
+                   "target_delay" => ?ctl.get_target_delay(),
+                   "velocity" => ctl.get_velocity(),
+                   "loss_rate" => ctl.get_loss_rate(),
+                   "congestive_loss_rate" => ctl.get_congestive_loss_rate(),
+                   "route_changes" => ctl.get_num_route_changes(),
+                   "mode" => ctl.get_mode().name(),
+                   "report_interval" => now - self.prev_report_time,
             );
         });
+        self.prev_report_time = now;
+        transitions
     }
 }
 
+impl<D: DatapathTrait> Drop for Copa<D> {
+    // Whatever led to a panic is worth seeing
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            if let Some(flight_recorder) = self.flight_recorder.as_mut() {
+                flight_recorder.dump(Anomaly::Panic, self.logger.as_ref());
+            }
+        }
+        // The rest of our aggregate no longer needs room for our part
+        if let Ok(mut ctl) = self.ctl.lock() {
+            ctl.remove_flow(self.sock_id);
+        }
+    }
+}
+
+const DATAPATH_PROGRAM: &str = "(def
+    (Report 
+        (volatile acked 0)
+        (volatile sacked 0) 
+        (volatile loss 0)
+        (volatile inflight 0)
+        (volatile timeout 0)
+        (volatile rtt 0)
+        (volatile now 0)
+        (volatile minrtt +infinity)
+   )
+    (basertt +infinity)
+)
+(when true
+    (:= Report.acked (+ Report.acked Ack.bytes_acked))
+    (:= Report.inflight Flow.packets_in_flight)
+    (:= Report.rtt Flow.rtt_sample_us)
+    (:= Report.minrtt (min Report.minrtt Flow.rtt_sample_us))
+    (:= basertt (min basertt Flow.rtt_sample_us))
+    (:= Report.sacked (+ Report.sacked Ack.packets_misordered))
+    (:= Report.loss Ack.lost_pkts_sample)
+    (:= Report.timeout Flow.was_timeout)
+    (:= Report.now Ack.now)
+    (fallthrough)
+)
+(when (|| Flow.was_timeout (> Report.loss 0))
+    (:= Micros 0)
+    (report)
+)
+(when (> Micros (/ basertt 2))
+    (:= Micros 0)
+    (report)
+)";
+
 impl<T: Ipc> CongAlg<T> for CopaConfig {
-    type Flow = Copa<T>;
+    type Flow = Copa<Datapath<T>>;
 
     fn name() -> &'static str {
         "copa"
     }
 
     fn datapath_programs(&self) -> HashMap<&'static str, String> {
-        vec![(
-            "copa",
-            "(def
-                (Report 
-                    (volatile acked 0)
-                    (volatile sacked 0) 
-                    (volatile loss 0)
-                    (volatile inflight 0)
-                    (volatile timeout 0)
-                    (volatile rtt 0)
-                    (volatile now 0)
-                    (volatile minrtt +infinity)
-               )
-                (basertt +infinity)
-            )
-            (when true
-                (:= Report.acked (+ Report.acked Ack.bytes_acked))
-                (:= Report.inflight Flow.packets_in_flight)
-                (:= Report.rtt Flow.rtt_sample_us)
-                (:= Report.minrtt (min Report.minrtt Flow.rtt_sample_us))
-                (:= basertt (min basertt Flow.rtt_sample_us))
-                (:= Report.sacked (+ Report.sacked Ack.packets_misordered))
-                (:= Report.loss Ack.lost_pkts_sample)
-                (:= Report.timeout Flow.was_timeout)
-                (:= Report.now Ack.now)
-                (fallthrough)
-            )
-            (when (|| Flow.was_timeout (> Report.loss 0))
-                (:= Micros 0)
-                (report)
-            )
-            (when (> Micros (/ basertt 2))
-                (:= Micros 0)
-                (report)
-            )"
-            .to_string(),
-        )]
-        .into_iter()
-        .collect()
+        vec![("copa", DATAPATH_PROGRAM.to_string())]
+            .into_iter()
+            .collect()
     }
 
     fn new_flow(&self, control: Datapath<T>, info: DatapathInfo) -> Self::Flow {
-        let mut s = Copa {
-            control_channel: control,
-            logger: self.logger.clone(),
-            cwnd: info.init_cwnd,
-            init_cwnd: info.init_cwnd,
-            sc: Default::default(),
-            delta_manager: DeltaManager::new(self.default_delta, self.delta_mode.clone()),
-            slow_start: true,
-            rtt_win: RTTWindow::new(),
-            velocity: 1,
-            cur_direction: 0,
-            prev_direction: 0,
-            time_since_direction: 0,
-            prev_update_rtt: 0,
-            agg_measurement: AggMeasurement::new(0.5),
-            prev_report_time: 0,
-        };
-
-        if self.init_cwnd != 0 {
-            s.cwnd = self.init_cwnd;
-            s.init_cwnd = self.init_cwnd;
-        }
-
-        self.logger.as_ref().map(|log| {
-            info!(log, "starting copa flow"; "sock_id" => info.sock_id);
-        });
-
-        s.sc = s.control_channel.set_program("copa", None).unwrap();
-        s.update();
-        s
+        Copa::new(self, control, info)
     }
 }
 
-impl<T: Ipc> portus::Flow for Copa<T> {
+impl<D: DatapathTrait> portus::Flow for Copa<D> {
     fn on_report(&mut self, _sock_id: u32, m: Report) {
-        let (report_status, was_timeout, acked, sacked, loss, _inflight, _rtt, min_rtt, now) =
-            self.agg_measurement.report(m, &self.sc);
-        if report_status == ReportStatus::UrgentReport {
-            if was_timeout {
-                self.handle_timeout();
-            }
-
-            self.delta_manager
-                .report_measurement(&mut self.rtt_win, 0, loss, now);
-        } else if report_status == ReportStatus::NoReport || acked + loss + sacked == 0 {
-            // Do nothing
-        } else {
-            // Record RTT
-            self.rtt_win.new_rtt_sample(min_rtt, now);
-            if self.rtt_win.did_base_rtt_change() {
-                self.control_channel
-                    .update_field(&self.sc, &[("base_rtt", self.rtt_win.get_base_rtt())])
-                    .unwrap();
-            }
-            // Update delta mode and delta
-            self.delta_manager
-                .report_measurement(&mut self.rtt_win, acked, loss, now);
-
-            // Increase/decrease the cwnd corresponding to new measurements
-            self.delay_control(min_rtt, acked, now);
-        }
-
-        // Send decisions to CCP
-        self.update();
-
-        self.logger.as_ref().map(|log| {
-            info!(log, "got ack";
-                   "acked(pkts)" => acked / 1448u32,
-                   "curr_cwnd (pkts)" => self.cwnd / 1460,
-                   "loss" => loss,
-                   "sacked" => sacked,
-                   "delta" => self.delta_manager.get_delta(),
-                   "min_rtt" => min_rtt,
-                   "base_rtt" => self.rtt_win.get_base_rtt(),
-                   "velocity" => self.velocity,
-                   "mode" => match self.delta_manager.get_mode() {
-                       DeltaMode::Default => "const",
-                       DeltaMode::TCPCoop => "tcp",
-                       DeltaMode::Loss => "loss",
-                   },
-                   "report_interval" => now - self.prev_report_time,
-            );
-        });
-        self.prev_report_time = now;
+        let m = Measurement::from_report(&m, &self.sc);
+        self.on_measurement(m);
     }
 }
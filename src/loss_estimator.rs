@@ -0,0 +1,182 @@
+use rtt_window::RTTWindow;
+
+// Weight of the latest cycle in the loss rate and in the fraction of
+// congestive losses
+const LOSS_RATE_ALPHA: f32 = 1. / 8.;
+const CONGESTIVE_ALPHA: f32 = 1. / 8.;
+
+// Estimates the loss rate, and how much of it is due to congestion. Losses
+// from a queue overflowing come when RTT is at its peak, while random losses
+// (e.g. on wireless links) come at any RTT
+pub struct LossEstimator {
+    // End of the current cycle, which lasts 2 base RTTs, and the number of
+    // packets acked and lost in it
+    cycle_end: u64,
+    cur_acked: u32,
+    cur_lost: u32,
+    // Losses in the current cycle we could classify, and those of them that
+    // came with the queue near its peak
+    cur_classified: u32,
+    cur_near_peak: u32,
+    // Average of the loss rates of past cycles
+    loss_rate: f32,
+    // Average over past cycles of the fraction of losses that came with the
+    // queue near its peak. A window's worth of losses can come within an RTT,
+    // so averaging over losses would only tell where in its oscillation the
+    // queue was. Without a queue to tell by, losses are as likely to be either
+    congestive: f32,
+}
+
+impl LossEstimator {
+    pub fn new() -> Self {
+        Self {
+            cycle_end: 0,
+            cur_acked: 0,
+            cur_lost: 0,
+            cur_classified: 0,
+            cur_near_peak: 0,
+            loss_rate: 0.,
+            congestive: 0.5,
+        }
+    }
+
+    // `acked` is in bytes and `lost` in packets, as reported by the datapath.
+    // `rtt` is the RTT seen along with them, if any
+    pub fn report(&mut self, rtt_win: &RTTWindow, acked: u32, lost: u32, rtt: u32, now: u64) {
+        self.cur_acked += acked / 1448;
+        self.cur_lost += lost;
+
+        if lost > 0 {
+            let base_rtt = rtt_win.get_base_rtt();
+            let max_rtt = rtt_win.get_recent_max_rtt();
+            let rtt = match rtt {
+                0 | u32::MAX => rtt_win.get_latest_rtt(),
+                rtt => rtt,
+            };
+            // Without a queue to speak of, we can't tell
+            if base_rtt != u32::MAX && max_rtt > base_rtt + 100 {
+                let near_peak = rtt >= base_rtt + (max_rtt - base_rtt) / 2;
+                self.cur_classified += lost;
+                if near_peak {
+                    self.cur_near_peak += lost;
+                }
+            }
+        }
+
+        if now > self.cycle_end {
+            if self.cur_acked + self.cur_lost > 0 {
+                let rate = self.cur_lost as f32 / (self.cur_acked + self.cur_lost) as f32;
+                self.loss_rate += LOSS_RATE_ALPHA * (rate - self.loss_rate);
+            }
+            if self.cur_classified > 0 {
+                let x = self.cur_near_peak as f32 / self.cur_classified as f32;
+                self.congestive += CONGESTIVE_ALPHA * (x - self.congestive);
+            }
+            // Cycles have no length till we know base RTT
+            self.cycle_end = match rtt_win.get_base_rtt() {
+                u32::MAX => now,
+                base_rtt => now + 2 * base_rtt as u64,
+            };
+            self.cur_acked = 0;
+            self.cur_lost = 0;
+            self.cur_classified = 0;
+            self.cur_near_peak = 0;
+        }
+    }
+
+    pub fn get_loss_rate(&self) -> f32 {
+        self.loss_rate
+    }
+
+    // Part of the loss rate that is due to congestion
+    pub fn get_congestive_loss_rate(&self) -> f32 {
+        self.loss_rate * self.congestive
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE_RTT: u32 = 40_000;
+    const STEP: u64 = 10_000;
+
+    // A queue that grows from empty to 40 ms over each cycle and then drains
+    // at once, with a report every 10 ms of 10 packets sent
+    struct Path {
+        est: LossEstimator,
+        rtt_win: RTTWindow,
+        now: u64,
+    }
+
+    impl Path {
+        fn new() -> Self {
+            Self {
+                est: LossEstimator::new(),
+                rtt_win: RTTWindow::new(10_000_000, false),
+                now: 1_000_000,
+            }
+        }
+
+        // `lost(rtt)` is the number of packets lost in a report
+        fn run<F: Fn(u32) -> u32>(&mut self, cycles: u64, lost: F) {
+            let steps = 2 * BASE_RTT as u64 / STEP;
+            for i in 0..cycles * steps {
+                self.now += STEP;
+                let rtt = BASE_RTT + (i % steps) as u32 * BASE_RTT / (steps - 1) as u32;
+                self.rtt_win.new_rtt_sample(rtt, self.now);
+                let lost = lost(rtt);
+                self.est.report(&self.rtt_win, (10 - lost) * 1448, lost, rtt, self.now);
+            }
+        }
+
+        fn congestive_fraction(&self) -> f32 {
+            self.est.get_congestive_loss_rate() / self.est.get_loss_rate()
+        }
+    }
+
+    #[test]
+    fn losses_at_the_peak_are_congestive() {
+        let mut path = Path::new();
+        path.run(50, |rtt| if rtt == 2 * BASE_RTT { 4 } else { 0 });
+        assert!((path.est.get_loss_rate() - 0.05).abs() < 0.01, "{}", path.est.get_loss_rate());
+        assert!(path.congestive_fraction() > 0.95, "{}", path.congestive_fraction());
+    }
+
+    // Losses that come at any point in the queue's oscillation count as
+    // congestive about as often as not
+    #[test]
+    fn losses_at_any_rtt_are_random() {
+        let mut path = Path::new();
+        path.run(50, |_| 1);
+        assert!((path.est.get_loss_rate() - 0.1).abs() < 0.01, "{}", path.est.get_loss_rate());
+        let fraction = path.congestive_fraction();
+        assert!(fraction > 0.4 && fraction < 0.6, "{}", fraction);
+    }
+
+    // A burst of losses reported all at once weighs no more than any other
+    // cycle's worth, however many packets it holds
+    #[test]
+    fn a_burst_of_losses_moves_the_estimate_by_one_cycle() {
+        let mut path = Path::new();
+        path.run(50, |rtt| if rtt == BASE_RTT { 2 } else { 0 });
+        let before = path.congestive_fraction();
+        assert!(before < 0.05, "{}", before);
+        path.run(1, |rtt| if rtt == 2 * BASE_RTT { 10 } else { 0 });
+        path.run(1, |rtt| if rtt == BASE_RTT { 2 } else { 0 });
+        let after = path.congestive_fraction();
+        assert!(after < before + CONGESTIVE_ALPHA, "{} -> {}", before, after);
+    }
+
+    #[test]
+    fn without_a_queue_losses_are_not_classified() {
+        let mut path = Path::new();
+        for _ in 0..1000 {
+            path.now += STEP;
+            path.rtt_win.new_rtt_sample(BASE_RTT, path.now);
+            path.est.report(&path.rtt_win, 9 * 1448, 1, BASE_RTT, path.now);
+        }
+        assert!((path.est.get_loss_rate() - 0.1).abs() < 0.01, "{}", path.est.get_loss_rate());
+        assert_eq!(path.congestive_fraction(), 0.5);
+    }
+}
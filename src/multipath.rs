@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use portus::DatapathInfo;
+
+// Latest window and RTT of a subflow
+#[derive(Clone, Copy)]
+struct Member {
+    cwnd: u32,
+    rtt: u32,
+}
+
+type Groups = Arc<Mutex<HashMap<u64, HashMap<u32, Member>>>>;
+
+/// Groups subflows of multipath (e.g. MPTCP) connections so that their
+/// controllers are coupled: on a shared bottleneck the subflows together take
+/// the share of a single Copa flow, and traffic shifts to the subflows whose
+/// paths have less queueing. The datapath does not tell which connection a
+/// subflow belongs to, so the caller supplies a key that does, e.g. from the
+/// ports its MPTCP stack gives subflows. Cloning gives another handle to the
+/// same groups.
+#[derive(Clone)]
+pub struct MultipathGroups {
+    // Subflows with the same key belong to one connection
+    key: fn(&DatapathInfo) -> u64,
+    groups: Groups,
+}
+
+impl MultipathGroups {
+    pub fn new(key: fn(&DatapathInfo) -> u64) -> Self {
+        Self {
+            key,
+            groups: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub(crate) fn join(&self, info: &DatapathInfo) -> Subflow {
+        let subflow = Subflow {
+            groups: self.groups.clone(),
+            key: (self.key)(info),
+            id: info.sock_id,
+        };
+        subflow.update(info.init_cwnd, 0);
+        subflow
+    }
+}
+
+// Membership of a flow in its group. The flow leaves the group when this is
+// dropped
+pub struct Subflow {
+    groups: Groups,
+    key: u64,
+    id: u32,
+}
+
+impl Subflow {
+    pub fn update(&self, cwnd: u32, rtt: u32) {
+        let mut groups = self.groups.lock().unwrap();
+        groups
+            .entry(self.key)
+            .or_default()
+            .insert(self.id, Member { cwnd, rtt });
+    }
+
+    // Sum of the windows of all subflows in our group, and sum of their rates
+    // in bytes/us. Subflows without an RTT sample don't count toward the rate
+    pub fn totals(&self) -> (u64, f64) {
+        let groups = self.groups.lock().unwrap();
+        let mut cwnd = 0u64;
+        let mut rate = 0f64;
+        if let Some(members) = groups.get(&self.key) {
+            for m in members.values() {
+                cwnd += m.cwnd as u64;
+                if m.rtt > 0 {
+                    rate += m.cwnd as f64 / m.rtt as f64;
+                }
+            }
+        }
+        (cwnd, rate)
+    }
+}
+
+impl Drop for Subflow {
+    fn drop(&mut self) {
+        let mut groups = self.groups.lock().unwrap();
+        let empty = match groups.get_mut(&self.key) {
+            Some(members) => {
+                members.remove(&self.id);
+                members.is_empty()
+            }
+            None => false,
+        };
+        if empty {
+            groups.remove(&self.key);
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Most destinations we keep entries for. Past this, the eighth of them
+// updated longest ago make way for new ones
+const MAX_ENTRIES: usize = 65_536;
+
+// What we remember about a destination from flows that recently went to it
+#[derive(Clone, Debug)]
+struct PathEntry {
+    base_rtt: u32,
+    cwnd: u32,
+    updated: Instant,
+}
+
+struct Entries {
+    map: HashMap<u32, PathEntry>,
+    // Entries past their TTL are dropped at most once per TTL
+    last_sweep: Instant,
+}
+
+/// Base RTT and window hints shared across flows, keyed on destination
+/// prefix, so that new flows to a recently seen destination need not start
+/// from scratch. Cloning the cache gives another handle to the same entries.
+#[derive(Clone)]
+pub struct PathCache {
+    // Number of leading bits of the destination address that identify a path
+    prefix_len: u8,
+    // Entries not updated for this long are not used
+    ttl: Duration,
+    entries: Arc<Mutex<Entries>>,
+}
+
+impl PathCache {
+    pub fn new(prefix_len: u8, ttl: Duration) -> Self {
+        if prefix_len > 32 {
+            panic!("Path cache prefix length should be at most 32.");
+        }
+        Self {
+            prefix_len,
+            ttl,
+            entries: Arc::new(Mutex::new(Entries {
+                map: HashMap::new(),
+                last_sweep: Instant::now(),
+            })),
+        }
+    }
+
+    /// Key for a destination address as reported in `DatapathInfo`, i.e. in
+    /// network byte order
+    pub fn key(&self, dst_ip: u32) -> u32 {
+        let addr = u32::from_be(dst_ip);
+        match self.prefix_len {
+            0 => 0,
+            l => addr & (u32::MAX << (32 - l as u32)),
+        }
+    }
+
+    /// Returns the (base RTT, cwnd) last recorded for the key, if still fresh
+    pub fn lookup(&self, key: u32) -> Option<(u32, u32)> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.map.get(&key) {
+            Some(e) if e.updated.elapsed() <= self.ttl => Some((e.base_rtt, e.cwnd)),
+            Some(_) => {
+                entries.map.remove(&key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn record(&self, key: u32, base_rtt: u32, cwnd: u32) {
+        let mut entries = self.entries.lock().unwrap();
+        // Flows record on every report, so only a new key may trigger a sweep
+        if !entries.map.contains_key(&key) {
+            if entries.last_sweep.elapsed() > self.ttl || entries.map.len() >= MAX_ENTRIES {
+                let ttl = self.ttl;
+                entries.map.retain(|_, e| e.updated.elapsed() <= ttl);
+                entries.last_sweep = Instant::now();
+            }
+            // Make room for a batch of new keys at once, so that this is rare
+            if entries.map.len() >= MAX_ENTRIES {
+                let mut times: Vec<Instant> = entries.map.values().map(|e| e.updated).collect();
+                times.sort_unstable();
+                let cutoff = times[MAX_ENTRIES / 8];
+                entries.map.retain(|_, e| e.updated > cutoff);
+            }
+        }
+        entries.map.insert(
+            key,
+            PathEntry {
+                base_rtt,
+                cwnd,
+                updated: Instant::now(),
+            },
+        );
+    }
+
+    /// Number of entries held, including any past their TTL that have not
+    /// been dropped yet
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
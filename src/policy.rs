@@ -0,0 +1,157 @@
+//! Per-flow bounds on rate and window, set on top of the control law.
+//!
+//! Deadlines are not supported. Copa does not know how many bytes a flow has
+//! left to send, so it cannot work out a rate from a deadline. A flow that
+//! must finish by some time can be given the `min_rate` that gets it there.
+//!
+//! The `copa` agent reads its policy file once, when it starts, so changing a
+//! policy there means restarting the agent. Programs that embed Copa can change
+//! a `PolicyTable` while flows run.
+
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use portus::DatapathInfo;
+
+/// Bounds put on a flow on top of what the control law decides, e.g. to keep
+/// background transfers from taking more than their due. Rates are in bytes
+/// per second and the window in bytes. Unset bounds don't apply
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FlowPolicy {
+    pub min_rate: Option<u32>,
+    pub max_rate: Option<u32>,
+    pub max_cwnd: Option<u32>,
+}
+
+/// Flows a policy applies to. Unset fields match any flow. Addresses and
+/// ports are as reported in `DatapathInfo`, i.e. in network byte order
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct FlowMatch {
+    pub sock_id: Option<u32>,
+    pub src_ip: Option<u32>,
+    pub src_port: Option<u32>,
+    pub dst_ip: Option<u32>,
+    pub dst_port: Option<u32>,
+}
+
+impl FlowMatch {
+    pub fn matches(&self, info: &DatapathInfo) -> bool {
+        let field = |want: Option<u32>, got: u32| want.is_none() || want == Some(got);
+        field(self.sock_id, info.sock_id)
+            && field(self.src_ip, info.src_ip)
+            && field(self.src_port, info.src_port)
+            && field(self.dst_ip, info.dst_ip)
+            && field(self.dst_port, info.dst_port)
+    }
+}
+
+/// Per-flow policies, looked up by socket id or 5-tuple. The first matching
+/// entry applies. Flows look their policy up on every update, so changes take
+/// effect on running flows. Cloning the table gives another handle to the
+/// same entries.
+#[derive(Clone, Default)]
+pub struct PolicyTable {
+    entries: Arc<Mutex<Vec<(FlowMatch, FlowPolicy)>>>,
+}
+
+impl PolicyTable {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Sets the policy of the flows matching `flows`, replacing the policy
+    /// previously set for exactly the same match
+    pub fn set(&self, flows: FlowMatch, policy: FlowPolicy) {
+        if let (Some(min), Some(max)) = (policy.min_rate, policy.max_rate) {
+            if min > max {
+                panic!("Flow policy min rate should be at most its max rate.");
+            }
+        }
+        let mut entries = self.entries.lock().unwrap();
+        match entries.iter_mut().find(|e| e.0 == flows) {
+            Some(e) => e.1 = policy,
+            None => entries.push((flows, policy)),
+        }
+    }
+
+    pub fn remove(&self, flows: &FlowMatch) {
+        self.entries.lock().unwrap().retain(|e| &e.0 != flows);
+    }
+
+    pub fn lookup(&self, info: &DatapathInfo) -> Option<FlowPolicy> {
+        let entries = self.entries.lock().unwrap();
+        entries.iter().find(|e| e.0.matches(info)).map(|e| e.1.clone())
+    }
+
+    /// Reads policies from a file with one entry per line, each a list of
+    /// `key=value` pairs. Keys `sock_id`, `src_ip`, `src_port`, `dst_ip` and
+    /// `dst_port` select flows, and `min_rate`, `max_rate` (bytes/s) and
+    /// `max_cwnd` (bytes) set bounds. Addresses are dotted quads. Lines
+    /// starting with `#` are comments, e.g.
+    ///
+    /// ```text
+    /// # Nightly backups get at most 1 MB/s
+    /// dst_port=873 max_rate=1000000
+    /// ```
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn parse(s: &str) -> io::Result<Self> {
+        let table = Self::new();
+        for (num, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let bad = |what: &str| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {}: {}", num + 1, what),
+                )
+            };
+            let mut flows = FlowMatch::default();
+            let mut policy = FlowPolicy::default();
+            for pair in line.split_whitespace() {
+                let mut kv = pair.splitn(2, '=');
+                let (key, value) = match (kv.next(), kv.next()) {
+                    (Some(key), Some(value)) => (key, value),
+                    _ => return Err(bad("expected key=value")),
+                };
+                let num = || value.parse::<u32>().map_err(|_| bad("bad number"));
+                // Ports as the datapath reports them, in network byte order
+                let port = || {
+                    value
+                        .parse::<u16>()
+                        .map(|p| p.to_be() as u32)
+                        .map_err(|_| bad("bad port"))
+                };
+                let ip = || {
+                    value
+                        .parse::<std::net::Ipv4Addr>()
+                        .map(|a| u32::from(a).to_be())
+                        .map_err(|_| bad("bad address"))
+                };
+                match key {
+                    "sock_id" => flows.sock_id = Some(num()?),
+                    "src_ip" => flows.src_ip = Some(ip()?),
+                    "src_port" => flows.src_port = Some(port()?),
+                    "dst_ip" => flows.dst_ip = Some(ip()?),
+                    "dst_port" => flows.dst_port = Some(port()?),
+                    "min_rate" => policy.min_rate = Some(num()?),
+                    "max_rate" => policy.max_rate = Some(num()?),
+                    "max_cwnd" => policy.max_cwnd = Some(num()?),
+                    _ => return Err(bad("unknown key")),
+                }
+            }
+            if let (Some(min), Some(max)) = (policy.min_rate, policy.max_rate) {
+                if min > max {
+                    return Err(bad("min_rate above max_rate"));
+                }
+            }
+            table.set(flows, policy);
+        }
+        Ok(table)
+    }
+}
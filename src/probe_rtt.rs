@@ -0,0 +1,61 @@
+#[derive(Clone, Debug)]
+pub struct ProbeRttConf {
+    // Time between probes (us). Probes start at multiples of this on the
+    // datapath's clock, so that flows on the same host drain together
+    pub interval: u64,
+    // Probes last at least this long (us), and at least two base RTTs so that
+    // the queue has time to drain
+    pub duration: u64,
+    // Window while probing, in packets
+    pub cwnd: u32,
+}
+
+// When many flows share a bottleneck the queue may never drain on its own,
+// leaving base RTT inflated. Every so often, cut the window to a few packets
+// for a while so that the queue empties and base RTT is measured afresh
+pub struct ProbeRtt {
+    conf: ProbeRttConf,
+    // End of the ongoing probe, if any
+    probe_end: Option<u64>,
+    // Number of the interval in which we last probed (or started)
+    last_interval: Option<u64>,
+}
+
+impl ProbeRtt {
+    pub fn new(conf: ProbeRttConf) -> Self {
+        if conf.interval == 0 {
+            panic!("Probe RTT interval should be positive.");
+        }
+        Self {
+            conf,
+            probe_end: None,
+            last_interval: None,
+        }
+    }
+
+    pub fn is_probing(&self) -> bool {
+        self.probe_end.is_some()
+    }
+
+    // Window to use while probing, in bytes
+    pub fn get_cwnd(&self) -> u32 {
+        self.conf.cwnd * 1448
+    }
+
+    // Start or end a probe as due. A flow first probes in the interval after
+    // the one it started in, and only once it knows its base RTT
+    pub fn on_report(&mut self, now: u64, base_rtt: u32) {
+        let interval = now / self.conf.interval;
+        match (self.probe_end, self.last_interval) {
+            (Some(end), _) if now >= end => self.probe_end = None,
+            (Some(_), _) => {}
+            (None, None) => self.last_interval = Some(interval),
+            (None, Some(last)) if interval > last && base_rtt != u32::MAX => {
+                self.last_interval = Some(interval);
+                self.probe_end =
+                    Some(now + std::cmp::max(self.conf.duration, 2 * base_rtt as u64));
+            }
+            (None, Some(_)) => {}
+        }
+    }
+}
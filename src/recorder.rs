@@ -0,0 +1,541 @@
+//! Recording of everything a Copa flow sees and decides, so that incidents can
+//! be replayed offline through the same control law. The controller's state
+//! after each report is recorded too, so that what a run did can be told
+//! without running any code again.
+//!
+//! A recording is a header describing the configuration, followed by records
+//! that each start with a tag and the socket id of the flow. All integers are
+//! little endian.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+
+use portus::DatapathInfo;
+
+use sim::SimDatapath;
+use {
+    Copa, CopaConfig, DeltaMode, DeltaModeConf, IdleRestartConf, LossDeltaConf, LossResponseConf, Measurement,
+    ProbeRttConf, SlowStartConf,
+};
+
+const MAGIC: &[u8; 8] = b"COPAREC\x09";
+
+const TAG_FLOW: u8 = 0;
+const TAG_MEASUREMENT: u8 = 1;
+const TAG_UPDATE: u8 = 2;
+const TAG_STATE: u8 = 3;
+
+// Flush after these many records, so little is lost if the agent is killed
+const FLUSH_INTERVAL: u32 = 1024;
+
+struct Sink {
+    // None once writing has failed, after which we stop recording
+    writer: Option<Box<dyn Write + Send>>,
+    num_unflushed: u32,
+    logger: Option<slog::Logger>,
+}
+
+impl Sink {
+    fn write(&mut self, buf: &[u8]) {
+        let w = match self.writer.as_mut() {
+            Some(w) => w,
+            None => return,
+        };
+        self.num_unflushed += 1;
+        let mut res = w.write_all(buf);
+        if res.is_ok() && self.num_unflushed >= FLUSH_INTERVAL {
+            self.num_unflushed = 0;
+            res = w.flush();
+        }
+        if let Err(e) = res {
+            self.writer = None;
+            if let Some(log) = self.logger.as_ref() {
+                warn!(log, "recording stopped, could not write to it"; "err" => ?e);
+            }
+        }
+    }
+}
+
+impl Drop for Sink {
+    fn drop(&mut self) {
+        if let Some(w) = self.writer.as_mut() {
+            let _ = w.flush();
+        }
+    }
+}
+
+/// Writes a recording. Cloning gives another handle to the same recording
+#[derive(Clone)]
+pub struct Recorder {
+    sink: Arc<Mutex<Sink>>,
+}
+
+impl Recorder {
+    /// Start a recording of flows that use the given configuration
+    pub fn new<W: Write + Send + 'static>(mut w: W, cfg: &CopaConfig) -> io::Result<Self> {
+        w.write_all(&encode_config(cfg))?;
+        Ok(Self {
+            sink: Arc::new(Mutex::new(Sink {
+                writer: Some(Box::new(w)),
+                num_unflushed: 0,
+                logger: cfg.logger.clone(),
+            })),
+        })
+    }
+
+    pub fn flow(&self, info: &DatapathInfo) {
+        let mut buf = record_header(TAG_FLOW, info.sock_id);
+        for x in &[
+            info.sock_id,
+            info.init_cwnd,
+            info.mss,
+            info.src_ip,
+            info.src_port,
+            info.dst_ip,
+            info.dst_port,
+        ] {
+            put_u32(&mut buf, *x);
+        }
+        self.sink.lock().unwrap().write(&buf);
+    }
+
+    pub fn measurement(&self, sock_id: u32, m: &Measurement) {
+        let mut buf = record_header(TAG_MEASUREMENT, sock_id);
+        put_u32(&mut buf, m.acked);
+        put_u32(&mut buf, m.sacked);
+        put_u32(&mut buf, m.loss);
+        buf.push(m.was_timeout as u8);
+        put_u32(&mut buf, m.inflight);
+        put_u32(&mut buf, m.rtt);
+        put_u32(&mut buf, m.min_rtt);
+        buf.extend_from_slice(&m.now.to_le_bytes());
+        self.sink.lock().unwrap().write(&buf);
+    }
+
+    /// An update of datapath registers, exactly as sent to the datapath
+    pub fn update(&self, sock_id: u32, update: &[(&str, u32)]) {
+        self.sink.lock().unwrap().write(&encode_update(sock_id, update));
+    }
+
+    /// The state of a flow's controller once it has acted on a measurement
+    pub fn state(&self, sock_id: u32, state: &FlowState) {
+        let mut buf = record_header(TAG_STATE, sock_id);
+        buf.push(DeltaMode::ALL.iter().position(|m| *m == state.mode).unwrap() as u8);
+        put_u32(&mut buf, state.delta.to_bits());
+        put_u32(&mut buf, state.velocity);
+        buf.push(state.slow_start as u8);
+        put_u32(&mut buf, state.base_rtt);
+        put_u32(&mut buf, state.cwnd);
+        self.sink.lock().unwrap().write(&buf);
+    }
+}
+
+/// What a flow's controller had decided after a measurement. The window is
+/// the controller's, before any policy, probe or share of an aggregate
+#[derive(Clone, Debug, PartialEq)]
+pub struct FlowState {
+    pub mode: DeltaMode,
+    pub delta: f32,
+    pub velocity: u32,
+    pub slow_start: bool,
+    pub base_rtt: u32,
+    pub cwnd: u32,
+}
+
+fn put_u32(buf: &mut Vec<u8>, x: u32) {
+    buf.extend_from_slice(&x.to_le_bytes());
+}
+
+fn record_header(tag: u8, sock_id: u32) -> Vec<u8> {
+    let mut buf = vec![tag];
+    put_u32(&mut buf, sock_id);
+    buf
+}
+
+fn encode_update(sock_id: u32, update: &[(&str, u32)]) -> Vec<u8> {
+    let mut buf = record_header(TAG_UPDATE, sock_id);
+    buf.push(update.len() as u8);
+    for &(name, value) in update {
+        buf.push(name.len() as u8);
+        buf.extend_from_slice(name.as_bytes());
+        put_u32(&mut buf, value);
+    }
+    buf
+}
+
+// Only what affects the control law of a single flow is recorded. Whether
+// flows shared state, or were under policies that may have changed while they
+// ran, is recorded so that replay can warn about it
+fn encode_config(cfg: &CopaConfig) -> Vec<u8> {
+    let mut buf = MAGIC.to_vec();
+    put_u32(&mut buf, cfg.init_cwnd);
+    put_u32(&mut buf, cfg.default_delta.to_bits());
+    match cfg.delta_mode {
+        DeltaModeConf::NoTCP => buf.push(0),
+        DeltaModeConf::Auto => buf.push(1),
+        DeltaModeConf::TargetDelay { target } => {
+            buf.push(2);
+            put_u32(&mut buf, target);
+        }
+        DeltaModeConf::Scavenger { target } => {
+            buf.push(3);
+            put_u32(&mut buf, target);
+        }
+    }
+    put_u32(&mut buf, cfg.loss_delta.min.to_bits());
+    put_u32(&mut buf, cfg.loss_delta.max.to_bits());
+    put_u32(&mut buf, cfg.loss_delta.step.to_bits());
+    buf.push(match cfg.idle_restart {
+        IdleRestartConf::Reset => 0,
+        IdleRestartConf::Decay => 1,
+        IdleRestartConf::Keep => 2,
+    });
+    match cfg.slow_start {
+        SlowStartConf::Classic => buf.push(0),
+        SlowStartConf::HyStart { rounds, max_growth } => {
+            buf.push(1);
+            put_u32(&mut buf, rounds);
+            put_u32(&mut buf, max_growth.to_bits());
+        }
+    }
+    put_u32(&mut buf, cfg.weight.to_bits());
+    match cfg.loss_response {
+        LossResponseConf::None => buf.push(0),
+        LossResponseConf::Decrease { beta } => {
+            buf.push(1);
+            put_u32(&mut buf, beta.to_bits());
+        }
+        LossResponseConf::Prr { beta } => {
+            buf.push(2);
+            put_u32(&mut buf, beta.to_bits());
+        }
+    }
+    buf.extend_from_slice(&cfg.rtt_history.to_le_bytes());
+    buf.push(cfg.detect_route_changes as u8);
+    match cfg.probe_rtt.as_ref() {
+        Some(conf) => {
+            buf.push(1);
+            buf.extend_from_slice(&conf.interval.to_le_bytes());
+            buf.extend_from_slice(&conf.duration.to_le_bytes());
+            put_u32(&mut buf, conf.cwnd);
+        }
+        None => buf.push(0),
+    }
+    buf.push(
+        (cfg.path_cache.is_some() as u8)
+            | (cfg.aggregator.is_some() as u8) << 1
+            | (cfg.multipath.is_some() as u8) << 2
+            | (cfg.policies.is_some() as u8) << 3,
+    );
+    buf
+}
+
+/// A record read back from a recording
+#[derive(Clone, Debug)]
+pub enum Record {
+    Flow(DatapathInfo),
+    Measurement(u32, Measurement),
+    Update(u32, Vec<(String, u32)>),
+    State(u32, FlowState),
+}
+
+/// Reads back a recording
+pub struct Reader<R: Read> {
+    r: R,
+}
+
+fn bad_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+impl<R: Read> Reader<R> {
+    /// Reads the header of a recording. Returns the reader, the configuration
+    /// of the recorded flows (without logger), and whether the flows shared
+    /// state with each other, in which case replay cannot be exact
+    pub fn new(mut r: R) -> io::Result<(Self, CopaConfig, bool)> {
+        let mut magic = [0u8; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(bad_data("not a Copa recording"));
+        }
+        let mut reader = Self { r };
+        let init_cwnd = reader.u32()?;
+        let default_delta = f32::from_bits(reader.u32()?);
+        let delta_mode = match reader.u8()? {
+            0 => DeltaModeConf::NoTCP,
+            1 => DeltaModeConf::Auto,
+            2 => DeltaModeConf::TargetDelay {
+                target: reader.u32()?,
+            },
+            3 => DeltaModeConf::Scavenger {
+                target: reader.u32()?,
+            },
+            _ => return Err(bad_data("unknown delta mode")),
+        };
+        let loss_delta = LossDeltaConf {
+            min: f32::from_bits(reader.u32()?),
+            max: f32::from_bits(reader.u32()?),
+            step: f32::from_bits(reader.u32()?),
+        };
+        let idle_restart = match reader.u8()? {
+            0 => IdleRestartConf::Reset,
+            1 => IdleRestartConf::Decay,
+            2 => IdleRestartConf::Keep,
+            _ => return Err(bad_data("unknown idle restart policy")),
+        };
+        let slow_start = match reader.u8()? {
+            0 => SlowStartConf::Classic,
+            1 => SlowStartConf::HyStart {
+                rounds: reader.u32()?,
+                max_growth: f32::from_bits(reader.u32()?),
+            },
+            _ => return Err(bad_data("unknown slow start policy")),
+        };
+        let weight = f32::from_bits(reader.u32()?);
+        let loss_response = match reader.u8()? {
+            0 => LossResponseConf::None,
+            1 => LossResponseConf::Decrease {
+                beta: f32::from_bits(reader.u32()?),
+            },
+            2 => LossResponseConf::Prr {
+                beta: f32::from_bits(reader.u32()?),
+            },
+            _ => return Err(bad_data("unknown loss response")),
+        };
+        let rtt_history = reader.u64()?;
+        let detect_route_changes = reader.u8()? != 0;
+        let probe_rtt = match reader.u8()? {
+            0 => None,
+            _ => Some(ProbeRttConf {
+                interval: reader.u64()?,
+                duration: reader.u64()?,
+                cwnd: reader.u32()?,
+            }),
+        };
+        let shared = reader.u8()? != 0;
+
+        let cfg = CopaConfig {
+            logger: None,
+            init_cwnd,
+            default_delta,
+            delta_mode,
+            loss_delta,
+            idle_restart,
+            slow_start,
+            loss_response,
+            rtt_history,
+            detect_route_changes,
+            path_cache: None,
+            weight,
+            aggregator: None,
+            multipath: None,
+            recorder: None,
+            flight_recorder: None,
+            probe_rtt,
+            policies: None,
+        };
+        Ok((reader, cfg, shared))
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        let mut b = [0u8; 1];
+        self.r.read_exact(&mut b)?;
+        Ok(b[0])
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let mut b = [0u8; 4];
+        self.r.read_exact(&mut b)?;
+        Ok(u32::from_le_bytes(b))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        let mut b = [0u8; 8];
+        self.r.read_exact(&mut b)?;
+        Ok(u64::from_le_bytes(b))
+    }
+
+    /// The next record, or None at the end of the recording
+    pub fn next_record(&mut self) -> io::Result<Option<Record>> {
+        let tag = match self.u8() {
+            Ok(tag) => tag,
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let sock_id = self.u32()?;
+        let record = match tag {
+            TAG_FLOW => Record::Flow(DatapathInfo {
+                sock_id: self.u32()?,
+                init_cwnd: self.u32()?,
+                mss: self.u32()?,
+                src_ip: self.u32()?,
+                src_port: self.u32()?,
+                dst_ip: self.u32()?,
+                dst_port: self.u32()?,
+            }),
+            TAG_MEASUREMENT => Record::Measurement(
+                sock_id,
+                Measurement {
+                    acked: self.u32()?,
+                    sacked: self.u32()?,
+                    loss: self.u32()?,
+                    was_timeout: self.u8()? != 0,
+                    inflight: self.u32()?,
+                    rtt: self.u32()?,
+                    min_rtt: self.u32()?,
+                    now: self.u64()?,
+                },
+            ),
+            TAG_UPDATE => {
+                let n = self.u8()?;
+                let mut update = Vec::new();
+                for _ in 0..n {
+                    let len = self.u8()?;
+                    let mut name = vec![0u8; len as usize];
+                    self.r.read_exact(&mut name)?;
+                    let name =
+                        String::from_utf8(name).map_err(|_| bad_data("bad register name"))?;
+                    update.push((name, self.u32()?));
+                }
+                Record::Update(sock_id, update)
+            }
+            TAG_STATE => Record::State(
+                sock_id,
+                FlowState {
+                    mode: match DeltaMode::ALL.get(self.u8()? as usize) {
+                        Some(mode) => mode.clone(),
+                        None => return Err(bad_data("unknown delta mode")),
+                    },
+                    delta: f32::from_bits(self.u32()?),
+                    velocity: self.u32()?,
+                    slow_start: self.u8()? != 0,
+                    base_rtt: self.u32()?,
+                    cwnd: self.u32()?,
+                },
+            ),
+            _ => return Err(bad_data("unknown record")),
+        };
+        Ok(Some(record))
+    }
+}
+
+/// Where a replay first produced a different update than the recording
+#[derive(Debug)]
+pub struct Divergence {
+    // Index of the update among all updates in the recording
+    pub index: usize,
+    pub sock_id: u32,
+    pub expected: Option<Vec<(String, u32)>>,
+    pub got: Option<Vec<(String, u32)>>,
+}
+
+/// Outcome of replaying a recording
+#[derive(Debug)]
+pub struct ReplaySummary {
+    pub num_flows: usize,
+    pub num_measurements: usize,
+    pub num_updates: usize,
+    // Whether the recorded flows shared state with each other
+    pub shared: bool,
+    pub divergence: Option<Divergence>,
+}
+
+/// A buffer to record into that can be read while the recorder still has it,
+/// e.g. to check a recording in tests. Clones share the same buffer
+#[derive(Clone, Default)]
+pub struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuf {
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+impl Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Updates in a sequence of records, each encoded as in a recording
+fn encoded_updates<R: Read>(reader: &mut Reader<R>) -> io::Result<Vec<Vec<u8>>> {
+    let mut updates = Vec::new();
+    while let Some(record) = reader.next_record()? {
+        if let Record::Update(sock_id, update) = record {
+            let update: Vec<(&str, u32)> = update.iter().map(|(n, v)| (n.as_str(), *v)).collect();
+            updates.push(encode_update(sock_id, &update));
+        }
+    }
+    Ok(updates)
+}
+
+/// Feed the measurements in a recording through Copa again, and check that
+/// every update it sends to the datapath is byte-identical to the recorded one
+pub fn replay<R: Read>(r: R) -> io::Result<ReplaySummary> {
+    let (mut reader, mut cfg, shared) = Reader::new(r)?;
+    // Collects updates produced during replay, in the recording's encoding
+    let collector = SharedBuf::default();
+    cfg.recorder = Some(Recorder::new(collector.clone(), &cfg)?);
+    // We only want the records, not the header
+    collector.0.lock().unwrap().clear();
+
+    let mut flows = HashMap::new();
+    let mut expected = Vec::new();
+    let mut summary = ReplaySummary {
+        num_flows: 0,
+        num_measurements: 0,
+        num_updates: 0,
+        shared,
+        divergence: None,
+    };
+    while let Some(record) = reader.next_record()? {
+        match record {
+            Record::Flow(info) => {
+                summary.num_flows += 1;
+                let dp = SimDatapath::new(info.sock_id);
+                flows.insert(info.sock_id, Copa::new(&cfg, dp, info));
+            }
+            Record::Measurement(sock_id, m) => {
+                summary.num_measurements += 1;
+                if let Some(flow) = flows.get_mut(&sock_id) {
+                    flow.on_measurement(m);
+                }
+            }
+            Record::Update(sock_id, update) => {
+                summary.num_updates += 1;
+                let update: Vec<(&str, u32)> =
+                    update.iter().map(|(n, v)| (n.as_str(), *v)).collect();
+                expected.push(encode_update(sock_id, &update));
+            }
+            Record::State(..) => {}
+        }
+    }
+
+    let produced = collector.contents();
+    let got = encoded_updates(&mut Reader { r: &produced[..] })?;
+    let decode = |buf: Option<&Vec<u8>>| {
+        buf.map(|buf| match (Reader { r: &buf[..] }).next_record() {
+            Ok(Some(Record::Update(sock_id, update))) => (sock_id, update),
+            _ => unreachable!(),
+        })
+    };
+    for index in 0..std::cmp::max(expected.len(), got.len()) {
+        if expected.get(index) != got.get(index) {
+            let (e, g) = (decode(expected.get(index)), decode(got.get(index)));
+            summary.divergence = Some(Divergence {
+                index,
+                sock_id: e.as_ref().or(g.as_ref()).map_or(0, |x| x.0),
+                expected: e.map(|x| x.1),
+                got: g.map(|x| x.1),
+            });
+            break;
+        }
+    }
+    Ok(summary)
+}
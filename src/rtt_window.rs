@@ -1,15 +1,34 @@
 use std;
 use std::collections::{VecDeque};
 
+use invariant;
+
+// The path is deemed to have changed when every RTT sample over this many
+// srtts is further above base RTT than the samples are spread out, the floor
+// is not coming down, there was no loss, and the RTT was at base right before
+const ROUTE_CHANGE_SRTTS: u64 = 30;
+
+// BBR drains the queue for ProbeRTT once it hasn't seen it empty for 10
+// seconds, and keeps it standing in between. A drain counts as a ProbeRTT dip
+// if the queue had stood for `BBR_PROBE_RTT_INTERVAL` +/- `BBR_PROBE_RTT_SLACK`
+// (us) before it. Whether the queue is empty is judged against the minimum RTT
+// over a few intervals, since BBR's standing queue may well pass for a route
+// change
+const BBR_PROBE_RTT_INTERVAL: u64 = 10_000_000;
+const BBR_PROBE_RTT_SLACK: u64 = 2_000_000;
+const BBR_FLOOR_HISTORY: u64 = 3 * BBR_PROBE_RTT_INTERVAL;
+
 pub struct RTTWindow {
-    // Maximum time till which to maintain history. It is minimum of 10s and 20
-    // RTTs.
-    max_time: u64,
+    // RTT samples are kept for at least this long (us). See `horizon`
+    min_history: u64,
+    // Whether to look for route changes
+    detect_route_changes: bool,
     // Base RTT
     base_rtt: u32,
-    // Did the base RTT change since the last rtt sample that was reported?
-    base_rtt_changed: bool,
     srtt: u32,
+    // Base RTT learnt from other flows, to be entered into history along with
+    // the first sample of our own
+    seed_rtt: Option<u32>,
 
     // RTT measurements
     rtts: VecDeque<u32>,
@@ -26,15 +45,34 @@ pub struct RTTWindow {
     // Number of increases and decreases in the current `increase` window
     num_increase: u32,
     num_decrease: u32,
+
+    // Set when an invariant was found violated. The owner should then reset
+    // us
+    violation: Option<&'static str>,
+    // Base RTT before and after the latest route change, till the owner takes
+    // note of it
+    route_change: Option<(u32, u32)>,
+    // Time of the latest report of loss
+    last_loss: u64,
+    // Minimum RTT since `floor_time`, for up to `BBR_FLOOR_HISTORY`
+    floor_rtt: u32,
+    floor_time: u64,
+    // Time of the latest sample that found the queue empty
+    last_empty: u64,
+    // Time of the latest report during a probe RTT of our own
+    last_own_probe: u64,
+    // Times of the latest two ProbeRTT dips
+    dips: VecDeque<u64>,
 }
 
 impl RTTWindow {
-    pub fn new() -> Self {
+    pub fn new(min_history: u64, detect_route_changes: bool) -> Self {
         Self {
-            max_time: 10_000_000,
+            min_history,
+            detect_route_changes,
             base_rtt: std::u32::MAX,
-            base_rtt_changed: false,
             srtt: 0,
+            seed_rtt: None,
 
             rtts: VecDeque::new(),
             times: VecDeque::new(),
@@ -44,36 +82,24 @@ impl RTTWindow {
             prev_min_rtt: 0, // We want to bias toward TCP mode
             num_increase: 0,
             num_decrease: 0,
+
+            violation: None,
+            route_change: None,
+            last_loss: 0,
+            floor_rtt: u32::MAX,
+            floor_time: 0,
+            last_empty: 0,
+            last_own_probe: 0,
+            dips: VecDeque::new(),
         }
     }
 
     fn clear_old_hist(&mut self, now: u64) {
-        assert!(self.rtts.len() == self.times.len());
-        // Whether or not min. RTT needs to be recomputed
-        let mut recompute_base_rtt = false;
-
-        // Delete all samples older than max_time. However, if there is only one
-        // sample left, don't delete it
-        while self.times.len() > 1 &&
-            self.times.front().unwrap() < &(now - self.max_time) {
-                if self.rtts.front().unwrap() <= &self.base_rtt {
-                    recompute_base_rtt = true;
-                }
-                self.times.pop_front();
-                self.rtts.pop_front();
-            }
-
-        // If necessary, recompute min rtt
-        if recompute_base_rtt {
-            self.base_rtt = std::u32::MAX;
-            self.base_rtt_changed = true;
-            for x in self.rtts.iter() {
-                if *x < self.base_rtt {
-                    self.base_rtt = *x;
-                }
-            }
-            assert!(self.base_rtt != std::u32::MAX);
+        if !invariant::check(self.rtts.len() == self.times.len(), "RTT history lengths differ") {
+            self.violation = Some("RTT history lengths differ");
+            return;
         }
+        self.evict_before(now.saturating_sub(self.horizon()));
 
         // Delete all old increase/decrease samples
         while self.increase.len() > 40 {
@@ -84,25 +110,101 @@ impl RTTWindow {
         }
     }
 
+    // Base RTT is the minimum RTT seen over this duration (us) before the
+    // latest sample. Long enough to outlast a period of persistent queueing,
+    // and at least 30 RTTs on long paths
+    fn horizon(&self) -> u64 {
+        std::cmp::max(self.min_history, 30 * self.srtt as u64)
+    }
+
+    // Forget history that went stale while the flow was idle. Queueing seen
+    // before the idle period (and hence TCP detection state) says nothing about
+    // the path now, and RTT samples older than the history horizon are dropped
+    // as usual, except for the latest one so that base RTT remains defined
+    pub fn age_out_idle(&mut self, now: u64) {
+        self.increase.clear();
+        self.num_increase = 0;
+        self.num_decrease = 0;
+        self.cur_min_rtt = u32::MAX;
+        self.prev_min_rtt = 0;
+        self.floor_rtt = u32::MAX;
+        self.last_empty = 0;
+        self.dips.clear();
+
+        self.evict_before(now.saturating_sub(self.horizon()));
+    }
+
+    // Delete all samples older than `cutoff`, recomputing base RTT if
+    // required. If there is only one sample left, don't delete it
+    fn evict_before(&mut self, cutoff: u64) {
+        let mut recompute_base_rtt = false;
+        while self.times.len() > 1 && self.times.front().unwrap() < &cutoff {
+            if self.rtts.front().unwrap() <= &self.base_rtt {
+                recompute_base_rtt = true;
+            }
+            self.times.pop_front();
+            self.rtts.pop_front();
+        }
+
+        if recompute_base_rtt {
+            self.base_rtt = self.rtts.iter().cloned().min().unwrap_or(u32::MAX);
+            if !invariant::check(self.base_rtt != u32::MAX, "base RTT undefined") {
+                self.violation = Some("base RTT undefined");
+            }
+        }
+    }
+
     pub fn get_base_rtt(&self) -> u32 {
         self.base_rtt
     }
 
-    pub fn did_base_rtt_change(&self) -> bool {
-        self.base_rtt_changed
+    // Use a base RTT from elsewhere until we have our own samples. It ages out
+    // of history as if it were measured along with our first sample
+    pub fn seed_base_rtt(&mut self, rtt: u32) {
+        if self.rtts.is_empty() {
+            self.base_rtt = rtt;
+            self.seed_rtt = Some(rtt);
+        }
+    }
+
+    pub fn take_violation(&mut self) -> Option<&'static str> {
+        self.violation.take()
+    }
+
+    pub fn get_latest_rtt(&self) -> u32 {
+        *self.rtts.back().unwrap_or(&0)
+    }
+
+    // Largest RTT over the last 10 srtts, as in TCP detection
+    pub fn get_recent_max_rtt(&self) -> u32 {
+        let latest = match self.times.back() {
+            Some(t) => *t,
+            None => return 0,
+        };
+        let start = latest.saturating_sub(10 * self.srtt as u64);
+        self.times
+            .iter()
+            .zip(self.rtts.iter())
+            .filter(|&(t, _)| *t > start)
+            .map(|(_, rtt)| *rtt)
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub fn get_srtt(&self) -> u32 {
+        self.srtt
     }
 
     pub fn new_rtt_sample(&mut self, rtt: u32, now: u64) {
-        assert!(self.rtts.len() == self.times.len());
-        self.max_time = std::cmp::max(10_000_000, 30 * self.srtt as u64);
-        if now < self.max_time {
-            self.max_time = 0;
-        }
-        else {
-            self.max_time = now - self.max_time;
+        if !invariant::check(self.rtts.len() == self.times.len(), "RTT history lengths differ") {
+            self.violation = Some("RTT history lengths differ");
+            return;
         }
-
         // Push back data
+        if let Some(seed) = self.seed_rtt.take() {
+            self.rtts.push_back(seed);
+            self.times.push_back(now);
+        }
         self.rtts.push_back(rtt);
         self.times.push_back(now);
 
@@ -133,6 +235,128 @@ impl RTTWindow {
 
         // Delete old data
         self.clear_old_hist(now);
+        self.detect_route_change();
+        self.detect_dip(rtt, now);
+    }
+
+    // Note drains of the queue that look like BBR's ProbeRTT. Loss-based TCP
+    // drains it too, but right after a loss, and so do we when probing RTT
+    // ourselves
+    fn detect_dip(&mut self, rtt: u32, now: u64) {
+        if rtt <= self.floor_rtt || now > self.floor_time + BBR_FLOOR_HISTORY {
+            self.floor_rtt = rtt;
+            self.floor_time = now;
+        }
+        let max = self.get_recent_max_rtt();
+        if rtt > self.floor_rtt + (max - self.floor_rtt) / 10 + 100 {
+            return;
+        }
+        if self.last_empty != 0
+            && now >= self.last_empty + BBR_PROBE_RTT_INTERVAL - BBR_PROBE_RTT_SLACK
+            && now <= self.last_empty + BBR_PROBE_RTT_INTERVAL + BBR_PROBE_RTT_SLACK
+            && self.last_loss + 2 * self.srtt as u64 <= now
+            && self.last_own_probe + 2 * self.srtt as u64 <= now
+        {
+            self.dips.push_back(now);
+            if self.dips.len() > 2 {
+                self.dips.pop_front();
+            }
+        }
+        self.last_empty = now;
+    }
+
+    // Whether BBR's next probe RTT is about due, a little before and after
+    // the queue will have stood for BBR's interval
+    pub fn bbr_probe_rtt_due(&self) -> bool {
+        if !self.bbr_detected() {
+            return false;
+        }
+        let next = self.last_empty + BBR_PROBE_RTT_INTERVAL;
+        let latest = *self.times.back().unwrap();
+        latest + 2 * self.srtt as u64 >= next && latest <= next + BBR_PROBE_RTT_SLACK / 4
+    }
+
+    // Whether the queue looks like it is kept by BBR: two ProbeRTT dips in a
+    // row, and the queue standing no longer than BBR would let it since. BBR's
+    // gain cycling shows in RTT every 8 RTTs, but so does Copa's own
+    // oscillation, so it is of no help
+    pub fn bbr_detected(&self) -> bool {
+        if self.dips.len() < 2 {
+            return false;
+        }
+        let (prev, last) = (self.dips[0], self.dips[1]);
+        let latest = *self.times.back().unwrap();
+        last - prev <= 2 * (BBR_PROBE_RTT_INTERVAL + BBR_PROBE_RTT_SLACK)
+            && latest <= last + 2 * (BBR_PROBE_RTT_INTERVAL + BBR_PROBE_RTT_SLACK)
+            && latest <= self.last_empty + BBR_PROBE_RTT_INTERVAL + BBR_PROBE_RTT_SLACK
+    }
+
+    // A move to a longer path looks like a standing queue that never drains,
+    // which would collapse the window until the old samples age out. Unlike
+    // queueing caused by a buffer-filling competitor, which comes and goes,
+    // the RTT after a route change stays close to a new floor. A queue we built
+    // ourselves drains as we back off, lowering the floor, and a queue kept
+    // full by anyone drops packets. A queue that many flows keep standing
+    // between them neither drains nor drops, but it builds up over many RTTs
+    // as they ramp up, whereas a route change moves the floor at once. When
+    // the floor has held for a while, forget samples from before and take it
+    // as base RTT
+    fn detect_route_change(&mut self) {
+        if !self.detect_route_changes || self.srtt == 0 || self.base_rtt == u32::MAX {
+            return;
+        }
+        let latest = *self.times.back().unwrap();
+        let window = ROUTE_CHANGE_SRTTS * self.srtt as u64;
+        if latest < window || *self.times.front().unwrap() >= latest - window {
+            // We don't have samples from before the window to compare against
+            return;
+        }
+        let start = latest - window;
+        if self.last_loss >= start || self.bbr_detected() {
+            return;
+        }
+
+        // Minimum over the earlier and later halves of the window, and the
+        // last sample before it
+        let mid = start + window / 2;
+        let (mut min_early, mut min_late) = (u32::MAX, u32::MAX);
+        let mut max = 0;
+        let mut before = u32::MAX;
+        for (t, rtt) in self.times.iter().zip(self.rtts.iter()) {
+            if *t < start {
+                before = *rtt;
+            } else if *t >= mid {
+                min_late = std::cmp::min(min_late, *rtt);
+            } else if *t >= start {
+                min_early = std::cmp::min(min_early, *rtt);
+            }
+            if *t >= start {
+                max = std::cmp::max(max, *rtt);
+            }
+        }
+        let min = std::cmp::min(min_early, min_late);
+        let shift = min - self.base_rtt;
+        if shift > self.base_rtt / 10 + 100
+            && max - min < shift
+            && min_early.saturating_sub(min_late) <= shift / 4
+            && before - self.base_rtt <= shift / 4
+        {
+            let old_base_rtt = self.base_rtt;
+            self.evict_before(start);
+            self.route_change = Some((old_base_rtt, self.base_rtt));
+        }
+    }
+
+    pub fn note_loss(&mut self, now: u64) {
+        self.last_loss = now;
+    }
+
+    pub fn note_own_probe_rtt(&mut self, now: u64) {
+        self.last_own_probe = now;
+    }
+
+    pub fn take_route_change(&mut self) -> Option<(u32, u32)> {
+        self.route_change.take()
     }
 
     pub fn tcp_detected(&mut self) -> bool {
@@ -157,9 +381,7 @@ impl RTTWindow {
         }
 
         let thresh = self.base_rtt + (max - self.base_rtt) / 10 + 100;
-        let res = min1 > thresh;
-        println!("min1: {}, max: {}, thresh: {}", min1, max, thresh);
-        res
+        min1 > thresh
     }
 
     pub fn num_tcp_detect_samples(&self) -> u32 {
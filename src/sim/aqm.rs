@@ -0,0 +1,374 @@
+//! Queue disciplines for the bottleneck. Besides drop-tail, these are models
+//! of the active queue management schemes deployed in practice, which drop
+//! packets well before the buffer is full to keep the queue short.
+
+use std::collections::VecDeque;
+
+use super::{Packet, Rng};
+
+/// How the bottleneck manages its queue. Delays are in us
+#[derive(Clone, Debug, PartialEq)]
+pub enum AqmConf {
+    /// Drop arriving packets only once the buffer is full
+    DropTail,
+    /// RED: drop arriving packets with a probability that grows from 0 to
+    /// `max_p` as the average queue grows from `min_thresh` to `max_thresh`
+    /// packets, and always beyond
+    Red {
+        min_thresh: f64,
+        max_thresh: f64,
+        max_p: f64,
+    },
+    /// CoDel (RFC 8289): drop departing packets at an increasing rate while
+    /// they have waited longer than `target` for at least `interval`
+    CoDel { target: u64, interval: u64 },
+    /// FQ-CoDel (RFC 8290): a CoDel queue per flow, served in round robin,
+    /// with flows that just became active served first
+    FqCoDel { target: u64, interval: u64 },
+    /// PIE (RFC 8033): drop arriving packets with a probability adjusted
+    /// every `update` so that the queueing delay stays near `target`
+    Pie { target: u64, update: u64 },
+}
+
+impl AqmConf {
+    /// RED with thresholds as commonly configured for the given buffer
+    pub fn red(buffer_pkts: usize) -> Self {
+        AqmConf::Red {
+            min_thresh: buffer_pkts as f64 / 8.,
+            max_thresh: buffer_pkts as f64 * 3. / 8.,
+            max_p: 0.1,
+        }
+    }
+
+    /// CoDel with the defaults of RFC 8289
+    pub fn codel() -> Self {
+        AqmConf::CoDel {
+            target: 5_000,
+            interval: 100_000,
+        }
+    }
+
+    /// FQ-CoDel with the defaults of RFC 8290
+    pub fn fq_codel() -> Self {
+        AqmConf::FqCoDel {
+            target: 5_000,
+            interval: 100_000,
+        }
+    }
+
+    /// PIE with the defaults of RFC 8033
+    pub fn pie() -> Self {
+        AqmConf::Pie {
+            target: 15_000,
+            update: 15_000,
+        }
+    }
+}
+
+// Weight of the latest sample in RED's average queue length
+const RED_WEIGHT: f64 = 0.002;
+// PIE's gains, per second of delay error and of delay change
+const PIE_ALPHA: f64 = 0.125;
+const PIE_BETA: f64 = 1.25;
+// PIE does not drop during bursts this long after the queue was last empty
+const PIE_MAX_BURST: u64 = 150_000;
+
+#[derive(Clone, Copy)]
+struct Queued {
+    pkt: Packet,
+    enqueued: u64,
+}
+
+// CoDel's state for one queue, named as in RFC 8289
+#[derive(Default)]
+struct CoDel {
+    first_above_time: u64,
+    drop_next: u64,
+    count: u32,
+    lastcount: u32,
+    dropping: bool,
+}
+
+impl CoDel {
+    fn control_law(&self, t: u64, interval: u64) -> u64 {
+        t + (interval as f64 / (self.count as f64).sqrt()) as u64
+    }
+
+    // Whether the packet at the head, which waited `sojourn`, may be dropped
+    fn ok_to_drop(
+        &mut self,
+        sojourn: u64,
+        backlog: usize,
+        now: u64,
+        target: u64,
+        interval: u64,
+    ) -> bool {
+        // Never drop the last packet, as a queue of one is no queue
+        if sojourn < target || backlog <= 1 {
+            self.first_above_time = 0;
+            return false;
+        }
+        if self.first_above_time == 0 {
+            self.first_above_time = now + interval;
+            return false;
+        }
+        now >= self.first_above_time
+    }
+
+    // Take the next packet to send from the queue, dropping as CoDel does
+    fn dequeue(
+        &mut self,
+        queue: &mut VecDeque<Queued>,
+        now: u64,
+        target: u64,
+        interval: u64,
+        dropped: &mut Vec<Packet>,
+    ) -> Option<Queued> {
+        let mut head = queue.pop_front()?;
+        let drop = self.ok_to_drop(now - head.enqueued, queue.len() + 1, now, target, interval);
+        if self.dropping {
+            if !drop {
+                self.dropping = false;
+            }
+            while self.dropping && now >= self.drop_next {
+                dropped.push(head.pkt);
+                self.count += 1;
+                head = match queue.pop_front() {
+                    Some(head) => head,
+                    None => {
+                        self.dropping = false;
+                        return None;
+                    }
+                };
+                if self.ok_to_drop(now - head.enqueued, queue.len() + 1, now, target, interval) {
+                    self.drop_next = self.control_law(self.drop_next, interval);
+                } else {
+                    self.dropping = false;
+                }
+            }
+        } else if drop {
+            dropped.push(head.pkt);
+            head = queue.pop_front()?;
+            // Keeps track of how long the new head has been above target
+            self.ok_to_drop(now - head.enqueued, queue.len() + 1, now, target, interval);
+            self.dropping = true;
+            // Start from where we left off if we were dropping recently
+            let delta = self.count.saturating_sub(self.lastcount);
+            self.count = match delta > 1 && now.saturating_sub(self.drop_next) < 16 * interval {
+                true => delta,
+                false => 1,
+            };
+            self.lastcount = self.count;
+            self.drop_next = self.control_law(now, interval);
+        }
+        Some(head)
+    }
+}
+
+#[derive(Default)]
+struct Pie {
+    drop_prob: f64,
+    qdelay_old: u64,
+    last_update: u64,
+    burst_allowance: u64,
+}
+
+/// The queue at the bottleneck and its discipline
+pub(super) struct Aqm {
+    conf: AqmConf,
+    // A single queue, or one per flow with fair queueing
+    queues: Vec<VecDeque<Queued>>,
+    len: usize,
+    codel: Vec<CoDel>,
+    // Flows with packets queued, as FQ-CoDel keeps them
+    new_flows: VecDeque<usize>,
+    old_flows: VecDeque<usize>,
+    red_avg: f64,
+    // Packets let in since RED last dropped
+    red_count: u32,
+    pie: Pie,
+    // Sojourn time of the packet sent last
+    last_sojourn: u64,
+}
+
+impl Aqm {
+    pub fn new(conf: AqmConf) -> Self {
+        Self {
+            conf,
+            queues: vec![VecDeque::new()],
+            len: 0,
+            codel: vec![CoDel::default()],
+            new_flows: VecDeque::new(),
+            old_flows: VecDeque::new(),
+            red_avg: 0.,
+            red_count: 0,
+            pie: Pie {
+                burst_allowance: PIE_MAX_BURST,
+                ..Default::default()
+            },
+            last_sojourn: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_fair_queueing(&self) -> bool {
+        matches!(self.conf, AqmConf::FqCoDel { .. })
+    }
+
+    pub fn last_sojourn(&self) -> u64 {
+        self.last_sojourn
+    }
+
+    // Returns false if the discipline drops the arriving packet. `delay` is
+    // how long it would take to drain the queue
+    pub fn enqueue(&mut self, pkt: Packet, now: u64, delay: u64, rng: &mut Rng) -> bool {
+        let drop = match self.conf {
+            AqmConf::Red {
+                min_thresh,
+                max_thresh,
+                max_p,
+            } => {
+                self.red_avg += RED_WEIGHT * (self.len as f64 - self.red_avg);
+                if self.red_avg < min_thresh {
+                    self.red_count = 0;
+                    false
+                } else if self.red_avg >= max_thresh {
+                    true
+                } else {
+                    // Spread drops out evenly, as RED does with its count
+                    let p = max_p * (self.red_avg - min_thresh) / (max_thresh - min_thresh);
+                    let p = p / (1. - (self.red_count as f64 * p).min(0.99));
+                    rng.next() < p
+                }
+            }
+            AqmConf::Pie { target, update } => {
+                self.pie_update(now, delay, target, update);
+                self.pie_should_drop(delay, target, rng)
+            }
+            _ => false,
+        };
+        if drop {
+            self.red_count = 0;
+            return false;
+        }
+        self.red_count += 1;
+
+        let q = match self.is_fair_queueing() {
+            true => pkt.flow,
+            false => 0,
+        };
+        while self.queues.len() <= q {
+            self.queues.push(VecDeque::new());
+            self.codel.push(CoDel::default());
+        }
+        if self.is_fair_queueing() && !self.new_flows.contains(&q) && !self.old_flows.contains(&q) {
+            self.new_flows.push_back(q);
+        }
+        self.queues[q].push_back(Queued { pkt, enqueued: now });
+        self.len += 1;
+        true
+    }
+
+    // The next packet to send, if any. Packets the discipline drops on the
+    // way out go to `dropped`
+    pub fn dequeue(&mut self, now: u64, dropped: &mut Vec<Packet>) -> Option<Packet> {
+        let num_dropped = dropped.len();
+        let head = match self.conf {
+            AqmConf::CoDel { target, interval } => {
+                self.codel[0].dequeue(&mut self.queues[0], now, target, interval, dropped)
+            }
+            AqmConf::FqCoDel { target, interval } => {
+                self.fq_dequeue(now, target, interval, dropped)
+            }
+            _ => self.queues[0].pop_front(),
+        };
+        self.len -= dropped.len() - num_dropped;
+        if self.len == 0 {
+            self.pie.burst_allowance = PIE_MAX_BURST;
+        }
+        head.map(|head| {
+            self.len -= 1;
+            self.last_sojourn = now - head.enqueued;
+            head.pkt
+        })
+    }
+
+    // With packets all the same size, deficit round robin with a quantum of a
+    // packet serves a packet from each flow in turn
+    fn fq_dequeue(
+        &mut self,
+        now: u64,
+        target: u64,
+        interval: u64,
+        dropped: &mut Vec<Packet>,
+    ) -> Option<Queued> {
+        loop {
+            let (q, new) = match self.new_flows.pop_front() {
+                Some(q) => (q, true),
+                None => (self.old_flows.pop_front()?, false),
+            };
+            match self.codel[q].dequeue(&mut self.queues[q], now, target, interval, dropped) {
+                Some(head) => {
+                    self.old_flows.push_back(q);
+                    return Some(head);
+                }
+                // A new flow that empties goes to the back of the old ones,
+                // so that it can't keep jumping the queue
+                None if new => self.old_flows.push_back(q),
+                None => {}
+            }
+        }
+    }
+
+    fn pie_update(&mut self, now: u64, delay: u64, target: u64, update: u64) {
+        if now < self.pie.last_update + update {
+            return;
+        }
+        let secs = |us: u64| us as f64 / 1e6;
+        let mut p = PIE_ALPHA * (secs(delay) - secs(target))
+            + PIE_BETA * (secs(delay) - secs(self.pie.qdelay_old));
+        // Move slowly while the probability is small, so that a few packets
+        // of delay don't swing it
+        let p_scale = match self.pie.drop_prob {
+            x if x < 0.000_001 => 1. / 2048.,
+            x if x < 0.000_01 => 1. / 512.,
+            x if x < 0.000_1 => 1. / 128.,
+            x if x < 0.001 => 1. / 32.,
+            x if x < 0.01 => 1. / 8.,
+            x if x < 0.1 => 1. / 2.,
+            _ => 1.,
+        };
+        p *= p_scale;
+        self.pie.drop_prob = (self.pie.drop_prob + p).clamp(0., 1.);
+        // Decay while the queue is empty
+        if delay == 0 && self.pie.qdelay_old == 0 {
+            self.pie.drop_prob *= 0.98;
+        }
+        self.pie.burst_allowance = self
+            .pie
+            .burst_allowance
+            .saturating_sub(now - self.pie.last_update);
+        self.pie.qdelay_old = delay;
+        self.pie.last_update = now;
+    }
+
+    fn pie_should_drop(&self, delay: u64, target: u64, rng: &mut Rng) -> bool {
+        if self.pie.burst_allowance > 0 {
+            return false;
+        }
+        if self.pie.qdelay_old < target / 2 && self.pie.drop_prob < 0.2 {
+            return false;
+        }
+        if self.len <= 2 || delay == 0 {
+            return false;
+        }
+        rng.next() < self.pie.drop_prob
+    }
+}
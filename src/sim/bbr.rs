@@ -0,0 +1,165 @@
+//! A model of BBR (v1) as a competitor for Copa. It keeps BBR's state machine
+//! and its estimates of bottleneck bandwidth and min RTT, which is what shapes
+//! the queue other flows see, and leaves out the rest.
+
+use std::collections::VecDeque;
+
+use super::MSS;
+
+const STARTUP_GAIN: f64 = 2.885;
+const CWND_GAIN: f64 = 2.;
+// Pacing gain in each phase of ProbeBW, each lasting a min RTT
+const PROBE_BW_GAINS: [f64; 8] = [1.25, 0.75, 1., 1., 1., 1., 1., 1.];
+// Bandwidth is the maximum delivery rate over this many rounds
+const BW_FILTER_ROUNDS: u64 = 10;
+// Min RTT is probed for when it hasn't been seen for this long (us)
+const MIN_RTT_EXPIRY: u64 = 10_000_000;
+const PROBE_RTT_DURATION: u64 = 200_000;
+const PROBE_RTT_CWND: u32 = 4;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum State {
+    Startup,
+    Drain,
+    ProbeBW,
+    ProbeRTT,
+}
+
+pub struct Bbr {
+    state: State,
+    // Delivery rate samples (bytes/s) by round, for the max filter
+    bw_samples: VecDeque<(u64, f64)>,
+    btl_bw: f64,
+    min_rtt: u32,
+    min_rtt_stamp: u64,
+
+    // Rounds end when a packet sent after the previous round ended is acked
+    round: u64,
+    round_end_delivered: u64,
+
+    // Startup ends when bandwidth stops growing by 25% for 3 rounds
+    full_bw: f64,
+    full_bw_rounds: u32,
+
+    cycle_index: usize,
+    cycle_start: u64,
+    probe_rtt_done: u64,
+}
+
+impl Bbr {
+    pub fn new() -> Self {
+        Self {
+            state: State::Startup,
+            bw_samples: VecDeque::new(),
+            btl_bw: 0.,
+            min_rtt: u32::MAX,
+            min_rtt_stamp: 0,
+            round: 0,
+            round_end_delivered: 0,
+            full_bw: 0.,
+            full_bw_rounds: 0,
+            cycle_index: 0,
+            cycle_start: 0,
+            probe_rtt_done: 0,
+        }
+    }
+
+    // On every ack, with the delivery rate measured over the flight of the
+    // acked packet. `delivered` is the total delivered so far and
+    // `sent_delivered` what was delivered when the packet was sent
+    pub fn on_ack(&mut self, rtt: u32, rate: f64, delivered: u64, sent_delivered: u64, inflight: u32, now: u64) {
+        let round_start = sent_delivered >= self.round_end_delivered;
+        if round_start {
+            self.round += 1;
+            self.round_end_delivered = delivered;
+        }
+
+        self.bw_samples.push_back((self.round, rate));
+        while self.bw_samples.front().unwrap().0 + BW_FILTER_ROUNDS <= self.round {
+            self.bw_samples.pop_front();
+        }
+        self.btl_bw = self.bw_samples.iter().map(|s| s.1).fold(0., f64::max);
+
+        let min_rtt_expired = now > self.min_rtt_stamp + MIN_RTT_EXPIRY;
+        if rtt <= self.min_rtt || min_rtt_expired {
+            self.min_rtt = rtt;
+            self.min_rtt_stamp = now;
+        }
+
+        match self.state {
+            State::Startup => {
+                if round_start {
+                    if self.btl_bw >= self.full_bw * 1.25 {
+                        self.full_bw = self.btl_bw;
+                        self.full_bw_rounds = 0;
+                    } else {
+                        self.full_bw_rounds += 1;
+                    }
+                    if self.full_bw_rounds >= 3 {
+                        self.state = State::Drain;
+                    }
+                }
+            }
+            State::Drain => {
+                if inflight * MSS <= self.bdp(1.) {
+                    self.enter_probe_bw(now);
+                }
+            }
+            State::ProbeBW => {
+                if now >= self.cycle_start + self.min_rtt as u64 {
+                    self.cycle_index = (self.cycle_index + 1) % PROBE_BW_GAINS.len();
+                    self.cycle_start = now;
+                }
+            }
+            State::ProbeRTT => {
+                if now >= self.probe_rtt_done {
+                    self.min_rtt_stamp = now;
+                    self.enter_probe_bw(now);
+                }
+            }
+        }
+
+        if min_rtt_expired && self.state != State::ProbeRTT && self.state != State::Startup {
+            self.state = State::ProbeRTT;
+            self.probe_rtt_done = now + std::cmp::max(PROBE_RTT_DURATION, self.min_rtt as u64);
+        }
+    }
+
+    fn enter_probe_bw(&mut self, now: u64) {
+        self.state = State::ProbeBW;
+        // Don't start every flow off probing, as BBR picks a phase at random
+        self.cycle_index = 2;
+        self.cycle_start = now;
+    }
+
+    fn bdp(&self, gain: f64) -> u32 {
+        (gain * self.btl_bw * self.min_rtt as f64 / 1e6) as u32
+    }
+
+    fn pacing_gain(&self) -> f64 {
+        match self.state {
+            State::Startup => STARTUP_GAIN,
+            State::Drain => 1. / STARTUP_GAIN,
+            State::ProbeBW => PROBE_BW_GAINS[self.cycle_index],
+            State::ProbeRTT => 1.,
+        }
+    }
+
+    /// Window in bytes
+    pub fn cwnd(&self) -> u32 {
+        if self.btl_bw == 0. || self.min_rtt == u32::MAX {
+            return 10 * MSS;
+        }
+        let cwnd = match self.state {
+            State::Startup | State::Drain => self.bdp(STARTUP_GAIN),
+            State::ProbeBW => self.bdp(CWND_GAIN),
+            State::ProbeRTT => PROBE_RTT_CWND * MSS,
+        };
+        std::cmp::max(cwnd, PROBE_RTT_CWND * MSS)
+    }
+
+    /// Pacing rate in bytes per second. 0 means unpaced
+    pub fn rate(&self) -> u32 {
+        (self.pacing_gain() * self.btl_bw) as u32
+    }
+}
@@ -0,0 +1,101 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use portus;
+use portus::lang::{Reg, Scope};
+use portus::DatapathTrait;
+
+use DATAPATH_PROGRAM;
+
+// Registers of the datapath program that CCP has written to, along with how
+// many times each was written
+#[derive(Default)]
+struct Registers {
+    values: HashMap<String, u32>,
+    num_updates: HashMap<String, usize>,
+}
+
+/// Stand-in for a datapath that runs Copa's program. It accepts updates the
+/// same way as the real datapath does, and remembers them so that the
+/// simulator (or a test) can act on them. Clones share the same registers.
+#[derive(Clone)]
+pub struct SimDatapath {
+    sock_id: u32,
+    sc: Scope,
+    regs: Rc<RefCell<Registers>>,
+}
+
+impl SimDatapath {
+    pub fn new(sock_id: u32) -> Self {
+        let (_, sc) = portus::lang::compile(DATAPATH_PROGRAM.as_bytes(), &[])
+            .expect("Copa's datapath program should compile");
+        Self {
+            sock_id,
+            sc,
+            regs: Rc::new(RefCell::new(Registers::default())),
+        }
+    }
+
+    /// Last value CCP wrote to the register, if any
+    pub fn get(&self, reg_name: &str) -> Option<u32> {
+        self.regs.borrow().values.get(reg_name).cloned()
+    }
+
+    /// Number of times CCP wrote to the register
+    pub fn num_updates(&self, reg_name: &str) -> usize {
+        *self.regs.borrow().num_updates.get(reg_name).unwrap_or(&0)
+    }
+
+    fn write(&self, update: &[(&str, u32)]) -> portus::Result<()> {
+        // Check everything first, so that a bad update changes nothing, like
+        // in the real datapath
+        for &(reg_name, _) in update {
+            if reg_name.starts_with("__") {
+                return Err(portus::Error(format!(
+                    "Cannot update reserved field: {:?}",
+                    reg_name
+                )));
+            }
+            match self.sc.get(reg_name) {
+                Some(&Reg::Control(..)) | Some(&Reg::Implicit(4, _)) | Some(&Reg::Implicit(5, _)) => {}
+                Some(_) => {
+                    return Err(portus::Error(format!("Cannot update field: {:?}", reg_name)))
+                }
+                None => return Err(portus::Error(format!("Unknown field: {:?}", reg_name))),
+            }
+        }
+
+        let mut regs = self.regs.borrow_mut();
+        for &(reg_name, value) in update {
+            regs.values.insert(reg_name.to_string(), value);
+            *regs.num_updates.entry(reg_name.to_string()).or_insert(0) += 1;
+        }
+        Ok(())
+    }
+}
+
+impl DatapathTrait for SimDatapath {
+    fn get_sock_id(&self) -> u32 {
+        self.sock_id
+    }
+
+    fn set_program(
+        &mut self,
+        program_name: &'static str,
+        fields: Option<&[(&str, u32)]>,
+    ) -> portus::Result<Scope> {
+        if program_name != "copa" {
+            return Err(portus::Error(format!(
+                "Map does not contain datapath program with key: {:?}",
+                program_name
+            )));
+        }
+        self.write(fields.unwrap_or(&[]))?;
+        Ok(self.sc.clone())
+    }
+
+    fn update_field(&self, _sc: &Scope, update: &[(&str, u32)]) -> portus::Result<()> {
+        self.write(update)
+    }
+}
@@ -0,0 +1,614 @@
+//! A small packet-level simulator in which Copa flows share a single
+//! bottleneck, possibly with competing BBR or NewReno flows. The bottleneck
+//! serves its queue at a constant rate or as a recorded trace of a real link
+//! says, and manages it with drop-tail or an AQM. Time advances in
+//! fixed ticks. Each Copa flow's datapath is emulated by running the same fold
+//! as Copa's datapath program on every ack, so the controllers see reports
+//! much like they would from a real datapath.
+
+use std::collections::VecDeque;
+
+use portus::DatapathInfo;
+
+use {Copa, CopaConfig, Measurement, ModeTransition};
+
+mod aqm;
+mod bbr;
+mod datapath;
+mod reno;
+mod trace;
+pub use self::aqm::AqmConf;
+use self::aqm::Aqm;
+use self::bbr::Bbr;
+use self::reno::Reno;
+pub use self::datapath::SimDatapath;
+pub use self::trace::Trace;
+
+pub const MSS: u32 = 1448;
+
+// Datapaths timestamp acks with the host's clock, which is far from zero when
+// a flow starts. Copa relies on this, so reports are timestamped this far
+// ahead of simulated time
+const DATAPATH_EPOCH: u64 = 1_000_000_000;
+
+#[derive(Clone, Copy, Debug)]
+struct Packet {
+    flow: usize,
+    sent: u64,
+    // Bytes the flow had delivered when the packet was sent, and when the
+    // latest of those was acked, to measure delivery rate
+    delivered: u64,
+    delivered_time: u64,
+}
+
+// xorshift64*, so that runs are reproducible without pulling in a crate
+struct Rng(u64);
+
+impl Rng {
+    // Uniform in [0, 1)
+    fn next(&mut self) -> f64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        (self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+// How the bottleneck's link takes packets off the queue
+enum Service {
+    Constant {
+        // Bytes per second
+        rate: f64,
+        // Bytes the link may transmit before it has to wait for more time
+        credit: f64,
+    },
+    Trace {
+        trace: Trace,
+        // Index of the next delivery opportunity
+        next: u64,
+    },
+}
+
+/// A queue served at a constant rate or as a recorded trace says, which may
+/// also drop packets at random as a lossy link would. It is drop-tail FIFO
+/// unless given another discipline
+pub struct Bottleneck {
+    service: Service,
+    // Maximum number of packets in the queue
+    buffer: usize,
+    queue: Aqm,
+    // Probability of dropping a packet regardless of the queue
+    loss_rate: f64,
+    rng: Rng,
+}
+
+impl Bottleneck {
+    pub fn new(rate_mbps: f64, buffer_pkts: usize) -> Self {
+        Self::with_aqm(rate_mbps, buffer_pkts, AqmConf::DropTail)
+    }
+
+    pub fn with_aqm(rate_mbps: f64, buffer_pkts: usize, aqm: AqmConf) -> Self {
+        let service = Service::Constant {
+            rate: rate_mbps * 1e6 / 8.,
+            credit: 0.,
+        };
+        Self::with_service(service, buffer_pkts, aqm)
+    }
+
+    /// A link that delivers packets when the trace says it can
+    pub fn with_trace(trace: Trace, buffer_pkts: usize, aqm: AqmConf) -> Self {
+        Self::with_service(Service::Trace { trace, next: 0 }, buffer_pkts, aqm)
+    }
+
+    fn with_service(service: Service, buffer_pkts: usize, aqm: AqmConf) -> Self {
+        Self {
+            service,
+            buffer: buffer_pkts,
+            queue: Aqm::new(aqm),
+            loss_rate: 0.,
+            rng: Rng(0x9e37_79b9_7f4a_7c15),
+        }
+    }
+
+    // Returns false if the packet was dropped
+    fn enqueue(&mut self, pkt: Packet, now: u64) -> bool {
+        if self.queue.len() >= self.buffer {
+            return false;
+        }
+        if self.loss_rate > 0. && self.rng.next() < self.loss_rate {
+            return false;
+        }
+        let delay = self.backlog_delay(now);
+        self.queue.enqueue(pkt, now, delay, &mut self.rng)
+    }
+
+    // Packets that finished transmission in the tick of length `dt` us ending
+    // at `now`, and those the discipline dropped instead
+    fn dequeue(&mut self, dt: u64, now: u64, out: &mut Vec<Packet>, dropped: &mut Vec<Packet>) {
+        match self.service {
+            Service::Constant {
+                rate,
+                ref mut credit,
+            } => {
+                *credit += rate * dt as f64 / 1e6;
+                while *credit >= MSS as f64 {
+                    match self.queue.dequeue(now, dropped) {
+                        Some(pkt) => {
+                            *credit -= MSS as f64;
+                            out.push(pkt);
+                        }
+                        None => break,
+                    }
+                }
+                // An idle link cannot save up transmission opportunities
+                if self.queue.is_empty() {
+                    *credit = credit.min(MSS as f64);
+                }
+            }
+            Service::Trace {
+                ref trace,
+                ref mut next,
+            } => {
+                // Opportunities that find the queue empty are lost, as in
+                // Mahimahi
+                while trace.opportunity(*next) <= now {
+                    *next += 1;
+                    if let Some(pkt) = self.queue.dequeue(now, dropped) {
+                        out.push(pkt);
+                    }
+                }
+            }
+        }
+    }
+
+    // Time it will take to drain the current queue, in us
+    fn backlog_delay(&self, now: u64) -> u64 {
+        match self.service {
+            Service::Constant { rate, .. } => {
+                (self.queue.len() as f64 * MSS as f64 * 1e6 / rate) as u64
+            }
+            Service::Trace { ref trace, next } => match self.queue.len() {
+                0 => 0,
+                len => trace.opportunity(next + len as u64 - 1).saturating_sub(now),
+            },
+        }
+    }
+
+    // Queueing delay packets see, in us. With fair queueing there is no one
+    // queue, so this is the time the packet sent last spent queued
+    fn queueing_delay(&self, now: u64) -> u64 {
+        match self.queue.is_fair_queueing() {
+            true => self.queue.last_sojourn(),
+            false => self.backlog_delay(now),
+        }
+    }
+}
+
+// State of the fold function in Copa's datapath program
+struct Fold {
+    acked: u32,
+    sacked: u32,
+    loss: u32,
+    inflight: u32,
+    rtt: u32,
+    min_rtt: u32,
+    now: u64,
+    base_rtt: u32,
+    // Number of times CCP had written to `basertt` when we last looked
+    base_rtt_updates: usize,
+    // Time at which `Micros` was last reset
+    micros_start: u64,
+}
+
+impl Fold {
+    fn new(now: u64) -> Self {
+        Self {
+            acked: 0,
+            sacked: 0,
+            loss: 0,
+            inflight: 0,
+            rtt: 0,
+            min_rtt: u32::MAX,
+            now,
+            base_rtt: u32::MAX,
+            base_rtt_updates: 0,
+            micros_start: now,
+        }
+    }
+
+    // Process one ack, returning a report if the program asks for one
+    fn on_ack(
+        &mut self,
+        dp: &SimDatapath,
+        acked: u32,
+        lost: u32,
+        rtt: u32,
+        inflight: u32,
+        now: u64,
+    ) -> Option<Measurement> {
+        // CCP may have overwritten the register since the last ack
+        if dp.num_updates("basertt") > self.base_rtt_updates {
+            self.base_rtt_updates = dp.num_updates("basertt");
+            self.base_rtt = dp.get("basertt").unwrap();
+        }
+
+        self.acked += acked;
+        self.inflight = inflight;
+        self.rtt = rtt;
+        self.min_rtt = std::cmp::min(self.min_rtt, rtt);
+        self.base_rtt = std::cmp::min(self.base_rtt, rtt);
+        self.loss = lost;
+        self.now = now;
+
+        let micros = now - self.micros_start;
+        if self.loss > 0 || micros > (self.base_rtt / 2) as u64 {
+            self.micros_start = now;
+            let m = Measurement {
+                acked: self.acked,
+                sacked: self.sacked,
+                loss: self.loss,
+                was_timeout: false,
+                inflight: self.inflight,
+                rtt: self.rtt,
+                min_rtt: self.min_rtt,
+                now: self.now + DATAPATH_EPOCH,
+            };
+            self.acked = 0;
+            self.sacked = 0;
+            self.loss = 0;
+            self.inflight = 0;
+            self.rtt = 0;
+            self.min_rtt = u32::MAX;
+            return Some(m);
+        }
+        None
+    }
+}
+
+// An ack (or loss notification) on its way back to the sender
+struct Ack {
+    arrival: u64,
+    pkt: Packet,
+    lost: bool,
+}
+
+// What decides how fast a flow sends
+#[allow(clippy::large_enum_variant)]
+enum Sender {
+    Copa {
+        cfg: CopaConfig,
+        // Controller and its datapath. Created when the flow starts
+        copa: Option<Copa<SimDatapath>>,
+        dp: SimDatapath,
+        fold: Fold,
+    },
+    Bbr(Bbr),
+    Reno(Reno),
+}
+
+struct SimFlow {
+    start: u64,
+    sender: Sender,
+    inflight: u32,
+    // Time the pacer last let a packet out
+    last_send: u64,
+    // The application has nothing to send till then
+    paused_until: u64,
+    acks: VecDeque<Ack>,
+    // Changes of delta mode, timestamped in simulated time
+    transitions: Vec<ModeTransition>,
+    // Bytes delivered by the end of each sampling interval
+    delivered: Vec<u64>,
+    cur_delivered: u64,
+    // Time the latest packet was acked
+    delivered_time: u64,
+}
+
+impl SimFlow {
+    // Keep acks in order of arrival
+    fn push_ack(&mut self, ack: Ack) {
+        let mut i = self.acks.len();
+        while i > 0 && self.acks[i - 1].arrival > ack.arrival {
+            i -= 1;
+        }
+        self.acks.insert(i, ack);
+    }
+}
+
+/// Flows sharing a bottleneck
+pub struct Simulator {
+    bottleneck: Bottleneck,
+    // Round trip propagation delay in us
+    prop_rtt: u64,
+    // Length of a tick in us
+    tick: u64,
+    // Throughput is recorded at this granularity, in us
+    sample_interval: u64,
+    now: u64,
+    flows: Vec<SimFlow>,
+    // Queueing delay at the bottleneck at the end of each sampling interval
+    queue_delays: Vec<u64>,
+}
+
+impl Simulator {
+    pub fn new(bottleneck: Bottleneck, prop_rtt: u64) -> Self {
+        Self {
+            bottleneck,
+            prop_rtt,
+            tick: 100,
+            sample_interval: 10_000,
+            now: 0,
+            flows: Vec::new(),
+            queue_delays: Vec::new(),
+        }
+    }
+
+    /// Add a flow that starts at the given time (in us). Returns its index
+    pub fn add_flow(&mut self, cfg: CopaConfig, start: u64) -> usize {
+        let id = self.flows.len();
+        self.push_flow(
+            Sender::Copa {
+                cfg,
+                copa: None,
+                dp: SimDatapath::new(id as u32),
+                fold: Fold::new(start),
+            },
+            start,
+        )
+    }
+
+    /// Add a BBR flow that starts at the given time (in us). Returns its index
+    pub fn add_bbr_flow(&mut self, start: u64) -> usize {
+        self.push_flow(Sender::Bbr(Bbr::new()), start)
+    }
+
+    /// Add a TCP NewReno flow that starts at the given time (in us). Returns
+    /// its index
+    pub fn add_reno_flow(&mut self, start: u64) -> usize {
+        self.push_flow(Sender::Reno(Reno::new()), start)
+    }
+
+    fn push_flow(&mut self, sender: Sender, start: u64) -> usize {
+        self.flows.push(SimFlow {
+            start,
+            sender,
+            inflight: 0,
+            last_send: 0,
+            paused_until: 0,
+            acks: VecDeque::new(),
+            transitions: Vec::new(),
+            delivered: Vec::new(),
+            cur_delivered: 0,
+            delivered_time: start,
+        });
+        self.flows.len() - 1
+    }
+
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    /// The datapath of the given Copa flow, to inspect what its controller
+    /// did
+    pub fn datapath(&self, flow: usize) -> &SimDatapath {
+        match self.flows[flow].sender {
+            Sender::Copa { ref dp, .. } => dp,
+            _ => panic!("flow {} is not a Copa flow", flow),
+        }
+    }
+
+    /// Changes of delta mode of the given flow so far
+    pub fn mode_transitions(&self, flow: usize) -> &[ModeTransition] {
+        &self.flows[flow].transitions
+    }
+
+    /// Whether the given Copa flow is still in slow start
+    pub fn in_slow_start(&self, flow: usize) -> bool {
+        match self.flows[flow].sender {
+            Sender::Copa { ref copa, .. } => match *copa {
+                Some(ref copa) => copa.in_slow_start(),
+                None => true,
+            },
+            _ => panic!("flow {} is not a Copa flow", flow),
+        }
+    }
+
+    /// Number of route changes the given Copa flow has detected so far
+    pub fn route_changes(&self, flow: usize) -> u32 {
+        match self.flows[flow].sender {
+            Sender::Copa { ref copa, .. } => copa.as_ref().map_or(0, |c| c.num_route_changes()),
+            _ => panic!("flow {} is not a Copa flow", flow),
+        }
+    }
+
+    /// Change the bottleneck's buffer, e.g. to cause an episode of congestive
+    /// loss. Packets already queued beyond it are still served
+    pub fn set_buffer(&mut self, buffer_pkts: usize) {
+        self.bottleneck.buffer = buffer_pkts;
+    }
+
+    /// Change the probability with which the bottleneck drops packets at
+    /// random
+    pub fn set_loss_rate(&mut self, loss_rate: f64) {
+        self.bottleneck.loss_rate = loss_rate;
+    }
+
+    /// Keep a flow from sending anything new till the given time (in us), as
+    /// if its application had nothing to send. What is in flight is still
+    /// acked
+    pub fn pause(&mut self, flow: usize, until: u64) {
+        self.flows[flow].paused_until = until;
+    }
+
+    /// Run the simulation till the given time (in us)
+    pub fn run_until(&mut self, end: u64) {
+        let mut departed = Vec::new();
+        let mut dropped = Vec::new();
+        while self.now < end {
+            self.now += self.tick;
+            let now = self.now;
+
+            // Transmit packets at the bottleneck
+            departed.clear();
+            dropped.clear();
+            self.bottleneck.dequeue(self.tick, now, &mut departed, &mut dropped);
+            for pkt in departed.iter() {
+                self.flows[pkt.flow].push_ack(Ack {
+                    arrival: now + self.prop_rtt,
+                    pkt: *pkt,
+                    lost: false,
+                });
+            }
+            // The sender learns of packets dropped on the way out once the
+            // packets behind them are acked
+            for pkt in dropped.iter() {
+                self.flows[pkt.flow].push_ack(Ack {
+                    arrival: now + self.prop_rtt,
+                    pkt: *pkt,
+                    lost: true,
+                });
+            }
+
+            for id in 0..self.flows.len() {
+                self.step_flow(id);
+            }
+
+            if now % self.sample_interval == 0 {
+                for flow in self.flows.iter_mut() {
+                    flow.delivered.push(flow.cur_delivered);
+                }
+                self.queue_delays.push(self.bottleneck.queueing_delay(now));
+            }
+        }
+    }
+
+    fn step_flow(&mut self, id: usize) {
+        let now = self.now;
+        let flow = &mut self.flows[id];
+        if now < flow.start {
+            return;
+        }
+        if let Sender::Copa { ref cfg, ref mut copa, ref dp, .. } = flow.sender {
+            if copa.is_none() {
+                let info = DatapathInfo {
+                    sock_id: id as u32,
+                    init_cwnd: 10 * MSS,
+                    mss: MSS,
+                    src_ip: 0,
+                    src_port: 0,
+                    dst_ip: 0,
+                    dst_port: 0,
+                };
+                *copa = Some(Copa::new(cfg, dp.clone(), info));
+            }
+        }
+
+        // Process acks that have arrived
+        while flow.acks.front().is_some_and(|a| a.arrival <= now) {
+            let ack = flow.acks.pop_front().unwrap();
+            flow.inflight -= 1;
+            let rtt = (now - ack.pkt.sent) as u32;
+            let (acked, lost) = if ack.lost { (0, 1) } else { (MSS, 0) };
+            if !ack.lost {
+                flow.cur_delivered += MSS as u64;
+                flow.delivered_time = now;
+            }
+            match flow.sender {
+                Sender::Copa { ref mut copa, ref dp, ref mut fold, .. } => {
+                    if let Some(m) = fold.on_ack(dp, acked, lost, rtt, flow.inflight, now) {
+                        let transitions = copa.as_mut().unwrap().on_measurement(m);
+                        flow.transitions
+                            .extend(transitions.into_iter().map(|t| ModeTransition {
+                                now: t.now - DATAPATH_EPOCH,
+                                ..t
+                            }));
+                    }
+                }
+                Sender::Bbr(ref mut bbr) => {
+                    if !ack.lost {
+                        let interval = std::cmp::max(now - ack.pkt.delivered_time, 1);
+                        let rate = (flow.cur_delivered - ack.pkt.delivered) as f64 * 1e6
+                            / interval as f64;
+                        bbr.on_ack(rtt, rate, flow.cur_delivered, ack.pkt.delivered, flow.inflight, now);
+                    }
+                }
+                Sender::Reno(ref mut reno) => match ack.lost {
+                    true => reno.on_loss(ack.pkt.sent, now),
+                    false => reno.on_ack(),
+                },
+            }
+        }
+
+        // Send what the window and pacer allow
+        if now < flow.paused_until {
+            return;
+        }
+        let (cwnd, rate) = match flow.sender {
+            Sender::Copa { ref dp, .. } => {
+                (dp.get("Cwnd").unwrap_or(10 * MSS), dp.get("Rate").unwrap_or(0))
+            }
+            Sender::Bbr(ref bbr) => (bbr.cwnd(), bbr.rate()),
+            Sender::Reno(ref reno) => (reno.cwnd(), 0),
+        };
+        let gap = match rate {
+            0 => 0,
+            rate => MSS as u64 * 1_000_000 / rate as u64,
+        };
+        while (flow.inflight + 1) * MSS <= cwnd && flow.last_send + gap <= now {
+            let pkt = Packet {
+                flow: id,
+                sent: now,
+                delivered: flow.cur_delivered,
+                delivered_time: flow.delivered_time,
+            };
+            flow.inflight += 1;
+            if !self.bottleneck.enqueue(pkt, now) {
+                // The sender learns of the loss once packets behind it are acked
+                flow.push_ack(Ack {
+                    arrival: now + self.bottleneck.backlog_delay(now) + self.prop_rtt,
+                    pkt,
+                    lost: true,
+                });
+            }
+            // Don't let the pacer save up for more than a tick's worth of
+            // packets
+            flow.last_send = std::cmp::max(flow.last_send + gap, now.saturating_sub(self.tick));
+        }
+    }
+
+    /// Average throughput of a flow between two times (in us), in bytes per
+    /// second. Times are rounded down to the sampling interval
+    pub fn throughput(&self, flow: usize, from: u64, to: u64) -> f64 {
+        let delivered = &self.flows[flow].delivered;
+        let idx = |t: u64| std::cmp::min((t / self.sample_interval) as usize, delivered.len());
+        let (i, j) = (idx(from), idx(to));
+        if j <= i || j == 0 {
+            return 0.;
+        }
+        let start = if i == 0 { 0 } else { delivered[i - 1] };
+        (delivered[j - 1] - start) as f64 * 1e6 / ((j - i) as u64 * self.sample_interval) as f64
+    }
+
+    /// Average queueing delay at the bottleneck between two times (in us), in
+    /// us. Times are rounded down to the sampling interval
+    pub fn queueing_delay(&self, from: u64, to: u64) -> f64 {
+        let idx = |t: u64| std::cmp::min((t / self.sample_interval) as usize, self.queue_delays.len());
+        let samples = &self.queue_delays[idx(from)..std::cmp::max(idx(from), idx(to))];
+        if samples.is_empty() {
+            return 0.;
+        }
+        samples.iter().sum::<u64>() as f64 / samples.len() as f64
+    }
+
+    /// Queueing delay at the bottleneck that the given fraction of samples
+    /// between two times (in us) are at or below, in us
+    pub fn queueing_delay_percentile(&self, from: u64, to: u64, p: f64) -> u64 {
+        let idx = |t: u64| std::cmp::min((t / self.sample_interval) as usize, self.queue_delays.len());
+        let mut samples = self.queue_delays[idx(from)..std::cmp::max(idx(from), idx(to))].to_vec();
+        if samples.is_empty() {
+            return 0;
+        }
+        samples.sort_unstable();
+        samples[std::cmp::min((p * samples.len() as f64) as usize, samples.len() - 1)]
+    }
+}
@@ -0,0 +1,47 @@
+//! A model of TCP NewReno as a competitor for Copa: slow start, additive
+//! increase of a packet per RTT, and halving the window once per round trip
+//! with loss. Like most TCP in the wild, it fills whatever buffer it is given.
+
+use super::MSS;
+
+pub struct Reno {
+    // In bytes
+    cwnd: u32,
+    ssthresh: u32,
+    // Packets sent before this time were in flight when we last cut the
+    // window, so their loss is part of the same congestion event
+    recovery_start: u64,
+}
+
+impl Reno {
+    pub fn new() -> Self {
+        Self {
+            cwnd: 10 * MSS,
+            ssthresh: u32::MAX,
+            recovery_start: 0,
+        }
+    }
+
+    pub fn on_ack(&mut self) {
+        if self.cwnd < self.ssthresh {
+            self.cwnd += MSS;
+        } else {
+            self.cwnd += std::cmp::max(MSS * MSS / self.cwnd, 1);
+        }
+    }
+
+    // On loss of a packet sent at `sent`
+    pub fn on_loss(&mut self, sent: u64, now: u64) {
+        if sent < self.recovery_start {
+            return;
+        }
+        self.ssthresh = std::cmp::max(self.cwnd / 2, 2 * MSS);
+        self.cwnd = self.ssthresh;
+        self.recovery_start = now;
+    }
+
+    /// Window in bytes
+    pub fn cwnd(&self) -> u32 {
+        self.cwnd
+    }
+}
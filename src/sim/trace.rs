@@ -0,0 +1,85 @@
+//! Packet delivery traces in Mahimahi's format, to serve the bottleneck at
+//! the capacity of a recorded link, e.g. an LTE or 5G one. Each line of a
+//! trace is a time in ms from the start of the trace at which the link can
+//! deliver one packet, and several lines with the same time are as many
+//! packets in that ms. Opportunities that find the queue empty go unused. The
+//! trace repeats once past its last line, so the last time is its period.
+
+use std::io;
+use std::path::Path;
+
+use super::MSS;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Trace {
+    // Delivery opportunities, in us from the start of the trace
+    opportunities: Vec<u64>,
+    // In us
+    period: u64,
+}
+
+impl Trace {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn parse(s: &str) -> io::Result<Self> {
+        let bad = |what: String| io::Error::new(io::ErrorKind::InvalidData, what);
+        let mut opportunities = Vec::new();
+        for (num, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let ms = line
+                .parse::<u64>()
+                .map_err(|_| bad(format!("line {}: bad timestamp", num + 1)))?;
+            if opportunities.last().is_some_and(|&last| ms * 1000 < last) {
+                return Err(bad(format!("line {}: timestamps go backwards", num + 1)));
+            }
+            opportunities.push(ms * 1000);
+        }
+        match opportunities.last() {
+            None => Err(bad("no delivery opportunities".to_string())),
+            Some(0) => Err(bad("trace should last at least 1 ms".to_string())),
+            Some(&period) => Ok(Self {
+                opportunities,
+                period,
+            }),
+        }
+    }
+
+    /// Length of the trace before it repeats, in us
+    pub fn period(&self) -> u64 {
+        self.period
+    }
+
+    /// Average capacity in bytes per second, with a packet per opportunity
+    pub fn rate(&self) -> f64 {
+        self.opportunities.len() as f64 * MSS as f64 * 1e6 / self.period as f64
+    }
+
+    /// Bytes the link can deliver from `from` till `to` (in us)
+    pub fn capacity(&self, from: u64, to: u64) -> f64 {
+        (self.num_before(to) - self.num_before(from)) as f64 * MSS as f64
+    }
+
+    // Time of the opportunity with the given index, counting from the start
+    // of the first repetition
+    pub(super) fn opportunity(&self, index: u64) -> u64 {
+        let len = self.opportunities.len() as u64;
+        index / len * self.period + self.opportunities[(index % len) as usize]
+    }
+
+    // Number of opportunities before the given time. The last opportunity
+    // of a repetition is at the same time as the start of the next one
+    fn num_before(&self, t: u64) -> u64 {
+        if t == 0 {
+            return 0;
+        }
+        let len = self.opportunities.len() as u64;
+        let offset = (t - 1) % self.period;
+        let within = self.opportunities.partition_point(|&o| o <= offset) as u64;
+        (t - 1) / self.period * len + within
+    }
+}
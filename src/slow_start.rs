@@ -0,0 +1,115 @@
+/// How a flow decides to leave slow start
+#[derive(Clone, Debug, PartialEq)]
+pub enum SlowStartConf {
+    /// Leave as soon as one report says the window should not grow
+    Classic,
+    /// Leave once the window should not have grown for `rounds` RTT rounds in
+    /// a row, or once acks stop arriving faster from one round to the next, as
+    /// in HyStart. The window grows at most `max_growth` times per round
+    HyStart { rounds: u32, max_growth: f32 },
+}
+
+// Slow start exit and growth, tracked over rounds of about an RTT each
+pub struct SlowStart {
+    conf: SlowStartConf,
+    // Start of the current round and the window then
+    round_start: u64,
+    round_cwnd: u32,
+    // Whether any report in the current round said the window should grow
+    round_increase: bool,
+    // Bytes acked in the current round
+    round_acked: u64,
+    // Rate at which acks arrived in the previous round (bytes/s), and how
+    // much the window grew over it
+    prev_ack_rate: f64,
+    prev_growth: f64,
+    // Consecutive rounds in which the delay said to stop
+    num_delay_rounds: u32,
+}
+
+impl SlowStart {
+    pub fn new(conf: SlowStartConf) -> Self {
+        if let SlowStartConf::HyStart { rounds, max_growth } = conf {
+            if rounds == 0 || max_growth <= 1. {
+                panic!("Slow start should take at least one round and grow by more than 1x.");
+            }
+        }
+        Self {
+            conf,
+            round_start: 0,
+            round_cwnd: 0,
+            round_increase: false,
+            round_acked: 0,
+            prev_ack_rate: 0.,
+            prev_growth: 0.,
+            num_delay_rounds: 0,
+        }
+    }
+
+    // Forget earlier rounds, when entering slow start again
+    pub fn restart(&mut self) {
+        *self = Self::new(self.conf.clone());
+    }
+
+    // Largest the window may grow to in the current round
+    pub fn max_cwnd(&self) -> u32 {
+        match self.conf {
+            SlowStartConf::Classic => u32::MAX,
+            SlowStartConf::HyStart { max_growth, .. } => {
+                (self.round_cwnd as f32 * max_growth) as u32
+            }
+        }
+    }
+
+    // Returns whether to leave slow start. A single report can be noisy, so
+    // HyStart only decides at the end of a round, based on all of it
+    pub fn on_report(
+        &mut self,
+        increase: bool,
+        acked: u32,
+        cwnd: u32,
+        srtt: u32,
+        now: u64,
+    ) -> bool {
+        let (rounds, max_growth) = match self.conf {
+            SlowStartConf::Classic => return !increase,
+            SlowStartConf::HyStart { rounds, max_growth } => (rounds, max_growth),
+        };
+        if self.round_start == 0 {
+            self.start_round(cwnd, now);
+        }
+        self.round_increase |= increase;
+        self.round_acked += acked as u64;
+        if now - self.round_start < srtt as u64 {
+            return false;
+        }
+
+        if self.round_increase {
+            self.num_delay_rounds = 0;
+        } else {
+            self.num_delay_rounds += 1;
+        }
+        // Acks arrive faster as the window grows until the bottleneck is full,
+        // after which they are spaced out by it instead. What is acked in a
+        // round was sent in the one before, so acks can only have sped up if
+        // the window grew then. A whole round of acks isn't noisy, so one
+        // round of this is enough
+        let min_growth = 1. + (max_growth as f64 - 1.) / 4.;
+        let ack_rate = self.round_acked as f64 * 1e6 / (now - self.round_start) as f64;
+        let rate_stalled = self.prev_ack_rate > 0.
+            && self.prev_growth >= min_growth
+            && ack_rate < self.prev_ack_rate * min_growth;
+        self.prev_ack_rate = ack_rate;
+        self.prev_growth = cwnd as f64 / self.round_cwnd as f64;
+
+        self.start_round(cwnd, now);
+        self.num_delay_rounds >= rounds || rate_stalled
+    }
+
+    fn start_round(&mut self, cwnd: u32, now: u64) {
+        self.round_start = now;
+        self.round_cwnd = cwnd;
+        self.round_increase = false;
+        self.round_acked = 0;
+    }
+}
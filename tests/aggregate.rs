@@ -0,0 +1,43 @@
+extern crate ccp_copa;
+extern crate portus;
+
+mod common;
+
+use ccp_copa::sim::{Bottleneck, Simulator};
+use ccp_copa::{Aggregator, CopaConfig, DeltaModeConf};
+use common::{config, LINK_RATE};
+
+// `k` flows in one aggregate and one flow on its own, on a 24 Mbit/s, 40 ms
+// path. Returns the throughput of each aggregated flow, and of the other
+fn run(k: usize) -> (Vec<f64>, f64) {
+    let mut sim = Simulator::new(Bottleneck::new(24., 1000), 40_000);
+    // All simulated flows go to the same host, so only those given the
+    // aggregator join it
+    let cfg = CopaConfig {
+        aggregator: Some(Aggregator::new(|_| 0)),
+        ..config(DeltaModeConf::NoTCP)
+    };
+    let members: Vec<_> = (0..k).map(|_| sim.add_flow(cfg.clone(), 0)).collect();
+    let other = sim.add_flow(config(DeltaModeConf::NoTCP), 0);
+    sim.run_until(30_000_000);
+    let members = members
+        .iter()
+        .map(|&flow| sim.throughput(flow, 10_000_000, 30_000_000))
+        .collect();
+    (members, sim.throughput(other, 10_000_000, 30_000_000))
+}
+
+#[test]
+fn aggregate_takes_one_share_split_evenly() {
+    for &k in &[2, 3, 8] {
+        let (members, other) = run(k);
+        let total: f64 = members.iter().sum();
+        assert!(total + other > 0.9 * LINK_RATE, "{}: {} + {}", k, total, other);
+        let ratio = total / other;
+        assert!(ratio > 0.8 && ratio < 1.25, "{}: aggregate {} against {}", k, total, other);
+        for t in members.iter() {
+            let part = t * k as f64 / total;
+            assert!(part > 0.9 && part < 1.1, "{}: {:?}", k, members);
+        }
+    }
+}
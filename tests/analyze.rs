@@ -0,0 +1,124 @@
+extern crate ccp_copa;
+extern crate portus;
+
+mod common;
+
+use std::io;
+
+use ccp_copa::analyze;
+use ccp_copa::recorder::{FlowState, Recorder, SharedBuf};
+use ccp_copa::sim::{Bottleneck, Simulator, MSS};
+use ccp_copa::{DeltaMode, DeltaModeConf, Measurement};
+use common::{config, info};
+
+// Two flows, the second starting at 2 s, recorded for 10 s
+fn record_run(delta_mode: DeltaModeConf) -> (Simulator, Vec<u8>) {
+    let buf = SharedBuf::default();
+    let mut cfg = config(delta_mode);
+    cfg.recorder = Some(Recorder::new(buf.clone(), &cfg).unwrap());
+    let mut sim = Simulator::new(Bottleneck::new(24., 400), 40_000);
+    sim.add_flow(cfg.clone(), 0);
+    sim.add_flow(cfg, 2_000_000);
+    sim.run_until(10_000_000);
+    let recording = buf.contents();
+    (sim, recording)
+}
+
+#[test]
+fn summary_matches_simulation() {
+    let (sim, recording) = record_run(DeltaModeConf::NoTCP);
+    let flows = analyze::analyze(&recording[..]).unwrap();
+    assert_eq!(flows.len(), 2);
+
+    for (f, flow) in flows.iter().enumerate() {
+        let start = f as u64 * 2_000_000;
+        let expected = (10_000_000 - start) as f64;
+        assert!((flow.duration as f64 - expected).abs() < 0.01 * expected, "duration {}", flow.duration);
+        let throughput = sim.throughput(f, start, 10_000_000);
+        assert!(
+            (flow.throughput() / throughput - 1.).abs() < 0.05,
+            "throughput {} vs {}",
+            flow.throughput(),
+            throughput
+        );
+
+        // Alone or not, Copa holds a few packets in the queue
+        let p50 = flow.queueing_delay_percentile(0.5);
+        let p95 = flow.queueing_delay_percentile(0.95);
+        let p99 = flow.queueing_delay_percentile(0.99);
+        assert!(p50 > 0 && p50 < 10_000);
+        assert!(p50 <= p95 && p95 <= p99);
+
+        // Never in another mode without TCP detection
+        assert_eq!(flow.mode_time(&DeltaMode::Default), flow.duration);
+        assert_eq!(flow.mode_time.iter().sum::<u64>(), flow.duration);
+        assert!(flow.slow_start_time > 0 && flow.slow_start_time < 1_500_000);
+        assert!(flow.num_velocity_resets > 0);
+    }
+}
+
+#[test]
+fn mode_time_follows_transitions() {
+    let (sim, recording) = record_run(DeltaModeConf::Auto);
+    let flows = analyze::analyze(&recording[..]).unwrap();
+    for (f, flow) in flows.iter().enumerate() {
+        let transitions = sim.mode_transitions(f);
+        assert_eq!(flow.mode_time.iter().sum::<u64>(), flow.duration);
+        if transitions.is_empty() {
+            assert_eq!(flow.mode_time(&DeltaMode::TCPCoop), flow.duration);
+        } else {
+            assert!(flow.mode_time(&transitions.last().unwrap().to) > 0, "{:?} {:?}", transitions, flow.mode_time);
+        }
+    }
+}
+
+// States that today's code would not get to from these reports, as if an
+// older build had made the recording. The summary is of what was recorded: a
+// flow without TCP detection never competes with TCP, and with every RTT
+// sample the same it sees no queue
+#[test]
+fn summary_is_of_recorded_states() {
+    let buf = SharedBuf::default();
+    let recorder = Recorder::new(buf.clone(), &config(DeltaModeConf::NoTCP)).unwrap();
+    recorder.flow(&info(1));
+    for i in 0..=100 {
+        recorder.measurement(
+            1,
+            &Measurement {
+                acked: 10 * MSS,
+                rtt: 40_000,
+                min_rtt: 40_000,
+                now: 1_000_000 + i * 10_000,
+                ..Default::default()
+            },
+        );
+        recorder.state(
+            1,
+            &FlowState {
+                mode: if i < 50 { DeltaMode::TCPCoop } else { DeltaMode::Loss },
+                delta: 0.5,
+                velocity: if i % 20 < 10 { 1 } else { 2 },
+                slow_start: i < 30,
+                base_rtt: 30_000,
+                cwnd: 10 * MSS,
+            },
+        );
+    }
+
+    let flows = analyze::analyze(&buf.contents()[..]).unwrap();
+    assert_eq!(flows.len(), 1);
+    let flow = &flows[0];
+    assert_eq!(flow.duration, 1_000_000);
+    assert_eq!(flow.bytes_acked, 101 * 10 * MSS as u64);
+    assert_eq!(flow.mode_time(&DeltaMode::TCPCoop), 500_000);
+    assert_eq!(flow.mode_time(&DeltaMode::Loss), 500_000);
+    assert_eq!(flow.slow_start_time, 300_000);
+    assert_eq!(flow.num_velocity_resets, 5);
+    assert_eq!(flow.queueing_delay_percentile(0.5), 10_000);
+}
+
+#[test]
+fn not_a_recording() {
+    let err = analyze::analyze(&b"not a recording"[..]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
@@ -0,0 +1,119 @@
+extern crate ccp_copa;
+extern crate portus;
+
+mod common;
+
+use ccp_copa::sim::{AqmConf, Bottleneck, Simulator};
+use ccp_copa::{DeltaMode, DeltaModeConf};
+use common::{config, LINK_RATE};
+
+const BUFFER: usize = 400;
+const END: u64 = 30_000_000;
+
+fn sim(aqm: AqmConf) -> Simulator {
+    Simulator::new(Bottleneck::with_aqm(24., BUFFER, aqm), 40_000)
+}
+
+fn aqms() -> Vec<AqmConf> {
+    vec![
+        AqmConf::red(BUFFER),
+        AqmConf::codel(),
+        AqmConf::fq_codel(),
+        AqmConf::pie(),
+    ]
+}
+
+// Fraction of the run an `Auto` flow spent competing with TCP
+fn tcp_fraction(sim: &Simulator, flow: usize) -> f64 {
+    let (mut time, mut last, mut in_tcp) = (0, 0, false);
+    for t in sim.mode_transitions(flow) {
+        if in_tcp {
+            time += t.now - last;
+        }
+        last = t.now;
+        in_tcp = t.to == DeltaMode::TCPCoop;
+    }
+    if in_tcp {
+        time += END - last;
+    }
+    time as f64 / END as f64
+}
+
+// The disciplines do what they are there for: Reno fills a drop-tail buffer,
+// but not a managed one
+#[test]
+fn aqm_keeps_reno_queue_short() {
+    let mut s = sim(AqmConf::DropTail);
+    s.add_reno_flow(0);
+    s.run_until(END);
+    assert!(s.queueing_delay(10_000_000, END) > 100_000.);
+
+    for aqm in aqms() {
+        let mut s = sim(aqm.clone());
+        let reno = s.add_reno_flow(0);
+        s.run_until(END);
+        let throughput = s.throughput(reno, 10_000_000, END);
+        let delay = s.queueing_delay(10_000_000, END);
+        assert!(throughput > 0.8 * LINK_RATE, "{:?}: throughput {}", aqm, throughput);
+        assert!(delay < 20_000., "{:?}: delay {}", aqm, delay);
+    }
+}
+
+// Copa alone keeps the queue well below any AQM's target, so it never sees
+// the discipline act
+#[test]
+fn copa_alone_is_unaffected() {
+    for aqm in aqms() {
+        let mut s = sim(aqm.clone());
+        let flow = s.add_flow(config(DeltaModeConf::Auto), 0);
+        s.run_until(END);
+        let throughput = s.throughput(flow, 10_000_000, END);
+        let delay = s.queueing_delay(10_000_000, END);
+        assert!(throughput > 0.95 * LINK_RATE, "{:?}: throughput {}", aqm, throughput);
+        assert!(delay < 5_000., "{:?}: delay {}", aqm, delay);
+        assert!(tcp_fraction(&s, flow) < 0.1, "{:?}: time in TCP mode {}", aqm, tcp_fraction(&s, flow));
+    }
+}
+
+#[test]
+fn copa_competes_with_reno_under_aqm() {
+    for aqm in aqms() {
+        let mut s = sim(aqm.clone());
+        let copa = s.add_flow(config(DeltaModeConf::Auto), 0);
+        let reno = s.add_reno_flow(0);
+        s.run_until(END);
+        let t_copa = s.throughput(copa, 10_000_000, END);
+        let t_reno = s.throughput(reno, 10_000_000, END);
+        let share = t_copa / (t_copa + t_reno);
+        assert!(share > 0.35 && share < 0.65, "{:?}: share {}", aqm, share);
+    }
+}
+
+// With a drop-tail buffer, Copa only holds its own against Reno by detecting
+// it. CoDel keeps the queue too short for Reno to show, and too short for
+// Copa to lose out if it doesn't. FQ-CoDel isolates flows from each other's
+// queue altogether, so Copa is rarely in TCP mode there
+#[test]
+fn tcp_detection_under_aqm() {
+    let run = |aqm: AqmConf, delta_mode: DeltaModeConf| {
+        let mut s = sim(aqm);
+        let copa = s.add_flow(config(delta_mode), 0);
+        let reno = s.add_reno_flow(0);
+        s.run_until(END);
+        let t_copa = s.throughput(copa, 10_000_000, END);
+        let t_reno = s.throughput(reno, 10_000_000, END);
+        (t_copa / (t_copa + t_reno), tcp_fraction(&s, copa))
+    };
+
+    let (_, drop_tail) = run(AqmConf::DropTail, DeltaModeConf::Auto);
+    let (_, fq_codel) = run(AqmConf::fq_codel(), DeltaModeConf::Auto);
+    assert!(drop_tail > 0.6, "time in TCP mode {}", drop_tail);
+    assert!(fq_codel < 0.2, "time in TCP mode {}", fq_codel);
+
+    let (drop_tail, _) = run(AqmConf::DropTail, DeltaModeConf::NoTCP);
+    assert!(drop_tail < 0.15, "time in TCP mode {}", drop_tail);
+    for aqm in [AqmConf::codel(), AqmConf::fq_codel()] {
+        let (share, _) = run(aqm.clone(), DeltaModeConf::NoTCP);
+        assert!(share > 0.4, "{:?}: share without TCP detection {}", aqm, share);
+    }
+}
@@ -0,0 +1,182 @@
+extern crate ccp_copa;
+extern crate portus;
+
+mod common;
+
+use ccp_copa::sim::{Bottleneck, SimDatapath, Simulator};
+use ccp_copa::{Copa, CopaConfig, DeltaModeConf, Measurement};
+use common::{config, info};
+
+// Feeds a Copa flow reports at the interval its datapath program would report
+// at, given the `basertt` CCP last wrote to it
+struct Driver {
+    copa: Copa<SimDatapath>,
+    dp: SimDatapath,
+    now: u64,
+    // `basertt` in the datapath and the number of writes to it we have seen
+    base_rtt: u32,
+    num_updates: usize,
+    // Time between the last two reports
+    interval: u64,
+}
+
+impl Driver {
+    fn new(rtt_history: u64) -> Self {
+        Self::with_config(CopaConfig {
+            rtt_history,
+            ..config(DeltaModeConf::NoTCP)
+        })
+    }
+
+    fn with_config(cfg: CopaConfig) -> Self {
+        let dp = SimDatapath::new(1);
+        Self {
+            copa: Copa::new(&cfg, dp.clone(), info(1)),
+            dp,
+            now: 1_000_000,
+            base_rtt: u32::MAX,
+            num_updates: 0,
+            interval: 0,
+        }
+    }
+
+    // Report every RTT sample being `rtt` till time `end`
+    fn run(&mut self, rtt: u32, end: u64) {
+        self.run_cycling(&[rtt], end);
+    }
+
+    // Report RTT samples cycling through `rtts` till time `end`
+    fn run_cycling(&mut self, rtts: &[u32], end: u64) {
+        let mut i = 0;
+        while self.now < end {
+            let rtt = rtts[i % rtts.len()];
+            i += 1;
+            if self.dp.num_updates("basertt") > self.num_updates {
+                self.num_updates = self.dp.num_updates("basertt");
+                self.base_rtt = self.dp.get("basertt").unwrap();
+            }
+            self.base_rtt = std::cmp::min(self.base_rtt, rtt);
+
+            self.interval = (self.base_rtt / 2) as u64;
+            self.now += self.interval;
+            self.copa.on_measurement(Measurement {
+                acked: 10 * 1448,
+                inflight: 20,
+                rtt,
+                min_rtt: rtt,
+                now: self.now,
+                ..Default::default()
+            });
+        }
+    }
+}
+
+#[test]
+fn base_rtt_written_once_per_change() {
+    let mut d = Driver::new(10_000_000);
+    d.run(20_000, 3_000_000);
+    assert_eq!(d.dp.num_updates("basertt"), 1);
+    assert_eq!(d.dp.get("basertt"), Some(20_000));
+    assert_eq!(d.interval, 10_000);
+}
+
+#[test]
+fn reporting_interval_tracks_base_rtt() {
+    let mut d = Driver::new(10_000_000);
+    d.run(20_000, 3_000_000);
+    // Once the 20ms samples age out of history, the base RTT goes up, which
+    // the datapath would not notice on its own
+    d.run(50_000, 16_000_000);
+    assert_eq!(d.dp.get("basertt"), Some(50_000));
+    assert_eq!(d.dp.num_updates("basertt"), 2);
+    assert_eq!(d.interval, 25_000);
+}
+
+// Samples are evicted once they are older than the history horizon, and not
+// before, after which the base RTT is the minimum of what remains
+#[test]
+fn base_rtt_rises_after_history() {
+    let mut d = Driver::new(10_000_000);
+    d.run(20_000, 3_000_000);
+    d.run(50_000, 12_900_000);
+    assert_eq!(d.dp.get("basertt"), Some(20_000));
+    d.run(50_000, 13_100_000);
+    assert_eq!(d.dp.get("basertt"), Some(50_000));
+}
+
+#[test]
+fn history_is_configurable() {
+    let mut d = Driver::new(2_000_000);
+    d.run(20_000, 3_000_000);
+    d.run(50_000, 4_900_000);
+    assert_eq!(d.dp.get("basertt"), Some(20_000));
+    d.run(50_000, 5_100_000);
+    assert_eq!(d.dp.get("basertt"), Some(50_000));
+}
+
+// On long paths history covers 30 RTTs, which here is ~18s
+#[test]
+fn history_covers_30_rtts() {
+    let mut d = Driver::new(10_000_000);
+    d.run(400_000, 5_000_000);
+    d.run(600_000, 20_000_000);
+    assert_eq!(d.dp.get("basertt"), Some(400_000));
+    d.run(600_000, 24_000_000);
+    assert_eq!(d.dp.get("basertt"), Some(600_000));
+}
+
+fn detecting_route_changes() -> Driver {
+    Driver::with_config(CopaConfig {
+        detect_route_changes: true,
+        ..config(DeltaModeConf::NoTCP)
+    })
+}
+
+// A move to a longer path is noticed within ROUTE_CHANGE_SRTTS srtts, well
+// before the old samples would have aged out
+#[test]
+fn route_change_resets_base_rtt() {
+    let mut d = detecting_route_changes();
+    d.run_cycling(&[20_000, 22_000], 3_000_000);
+    d.run_cycling(&[40_000, 42_000], 4_000_000);
+    assert_eq!(d.dp.get("basertt"), Some(20_000));
+    d.run_cycling(&[40_000, 42_000], 4_500_000);
+    assert_eq!(d.dp.get("basertt"), Some(40_000));
+    assert_eq!(d.copa.num_route_changes(), 1);
+}
+
+// A queue that builds up over a while is not a route change, even if it
+// never drains
+#[test]
+fn gradual_rise_is_not_a_route_change() {
+    let mut d = detecting_route_changes();
+    d.run(20_000, 3_000_000);
+    for step in 1..=20 {
+        let rtt = 20_000 + step * 1_000;
+        d.run_cycling(&[rtt, rtt + 2_000], 3_000_000 + step as u64 * 100_000);
+    }
+    d.run_cycling(&[40_000, 42_000], 8_000_000);
+    assert_eq!(d.dp.get("basertt"), Some(20_000));
+    assert_eq!(d.copa.num_route_changes(), 0);
+}
+
+// Many Copa flows keep a queue standing between them that never drains, which
+// must not pass for a route change
+#[test]
+fn standing_queue_of_many_flows_is_not_a_route_change() {
+    for &(rate, prop_rtt) in &[(12., 40_000), (24., 100_000)] {
+        let mut sim = Simulator::new(Bottleneck::new(rate, 1000), prop_rtt);
+        let cfg = CopaConfig {
+            detect_route_changes: true,
+            ..config(DeltaModeConf::NoTCP)
+        };
+        let flows: Vec<usize> = (0..8).map(|i| sim.add_flow(cfg.clone(), i * 500_000)).collect();
+        sim.run_until(30_000_000);
+        // The queue does stand
+        let q = sim.queueing_delay_percentile(10_000_000, 30_000_000, 0.1);
+        assert!(q > prop_rtt / 2, "{}", q);
+        for &flow in flows.iter() {
+            assert_eq!(sim.route_changes(flow), 0, "flow {} at {} Mbit/s", flow, rate);
+        }
+    }
+}
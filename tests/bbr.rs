@@ -0,0 +1,75 @@
+extern crate ccp_copa;
+extern crate portus;
+
+mod common;
+
+use ccp_copa::sim::{Bottleneck, Simulator};
+use ccp_copa::{CopaConfig, DeltaMode, DeltaModeConf, ProbeRttConf};
+use common::config;
+
+fn num_bbr_detections(sim: &Simulator, flows: &[usize]) -> usize {
+    flows
+        .iter()
+        .map(|&f| {
+            sim.mode_transitions(f)
+                .iter()
+                .filter(|t| t.to == DeltaMode::BBRCoop)
+                .count()
+        })
+        .sum()
+}
+
+#[test]
+fn bbr_is_detected_by_its_probe_rtt() {
+    let mut sim = Simulator::new(Bottleneck::new(24., 1000), 40_000);
+    let copa = sim.add_flow(config(DeltaModeConf::Auto), 0);
+    let bbr = sim.add_bbr_flow(0);
+    sim.run_until(90_000_000);
+
+    // BBR probes RTT every 10 s or so. Till Copa cooperates, its own queue
+    // keeps the probes from draining the queue down to the RTT of the empty
+    // path that Copa saw at the start. So the probes give BBR away only once
+    // that has aged out of the floor the dips are judged against, after 30 s
+    let transitions = sim.mode_transitions(copa);
+    let detected: Vec<_> = transitions
+        .iter()
+        .filter(|t| t.to == DeltaMode::BBRCoop)
+        .collect();
+    assert_eq!(detected.len(), 1, "{:?}", transitions);
+    assert!(detected[0].now > 30_000_000 && detected[0].now < 60_000_000, "{:?}", detected[0]);
+    assert_eq!(transitions.last().unwrap().to, DeltaMode::BBRCoop);
+
+    // Delay-based, Copa would get a small fraction of what BBR does
+    let t_copa = sim.throughput(copa, 60_000_000, 90_000_000);
+    let t_bbr = sim.throughput(bbr, 60_000_000, 90_000_000);
+    assert!(t_copa / (t_copa + t_bbr) > 0.35, "copa {} bbr {}", t_copa, t_bbr);
+}
+
+#[test]
+fn copa_is_not_mistaken_for_bbr() {
+    let mut sim = Simulator::new(Bottleneck::new(24., 1000), 40_000);
+    let flows: Vec<_> = (0..4)
+        .map(|_| sim.add_flow(config(DeltaModeConf::Auto), 0))
+        .collect();
+    sim.run_until(60_000_000);
+    assert_eq!(num_bbr_detections(&sim, &flows), 0);
+}
+
+#[test]
+fn own_probe_rtt_is_not_mistaken_for_bbr() {
+    // Enough flows that the queue stands between probes
+    let cfg = CopaConfig {
+        probe_rtt: Some(ProbeRttConf {
+            interval: 10_000_000,
+            duration: 200_000,
+            cwnd: 4,
+        }),
+        ..config(DeltaModeConf::Auto)
+    };
+    let mut sim = Simulator::new(Bottleneck::new(24., 1000), 40_000);
+    let flows: Vec<_> = (0..6)
+        .map(|i| sim.add_flow(cfg.clone(), i * 1_000_000))
+        .collect();
+    sim.run_until(60_000_000);
+    assert_eq!(num_bbr_detections(&sim, &flows), 0);
+}
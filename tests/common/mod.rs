@@ -0,0 +1,30 @@
+//! Helpers shared by the integration tests. Each test binary uses some of them
+
+#![allow(dead_code)]
+
+use ccp_copa::sim::MSS;
+use ccp_copa::{CopaConfig, DeltaModeConf};
+use portus::DatapathInfo;
+
+/// Bytes per second of the 24 Mbit/s bottleneck most tests run on
+pub const LINK_RATE: f64 = 3e6;
+
+pub fn config(delta_mode: DeltaModeConf) -> CopaConfig {
+    CopaConfig {
+        delta_mode,
+        ..Default::default()
+    }
+}
+
+/// A flow with a 10 packet initial window, between hosts that don't matter
+pub fn info(sock_id: u32) -> DatapathInfo {
+    DatapathInfo {
+        sock_id,
+        init_cwnd: 10 * MSS,
+        mss: MSS,
+        src_ip: 0,
+        src_port: 0,
+        dst_ip: 0,
+        dst_port: 0,
+    }
+}
@@ -0,0 +1,69 @@
+extern crate ccp_copa;
+extern crate portus;
+
+mod common;
+
+use ccp_copa::sim::{Bottleneck, Simulator, MSS};
+use ccp_copa::{CopaConfig, DeltaModeConf, Equilibrium};
+use common::config;
+
+// Run flows with the given deltas on a deep buffer, and check that rates, the
+// average queueing delay and its swing agree with the analysis
+fn check(rate_mbps: f64, prop_rtt: u64, deltas: &[f32]) {
+    let eq = Equilibrium::new(rate_mbps * 1e6 / 8., prop_rtt, MSS, deltas, 10 * MSS);
+    assert!(!eq.window_limited);
+
+    let mut sim = Simulator::new(Bottleneck::new(rate_mbps, 10_000), prop_rtt);
+    let flows: Vec<_> = deltas
+        .iter()
+        .map(|&default_delta| {
+            let cfg = CopaConfig {
+                default_delta,
+                ..config(DeltaModeConf::NoTCP)
+            };
+            sim.add_flow(cfg, 0)
+        })
+        .collect();
+    sim.run_until(40_000_000);
+    let (from, to) = (20_000_000, 40_000_000);
+    let delay = sim.queueing_delay(from, to);
+    let swing = (sim.queueing_delay_percentile(from, to, 0.95)
+        - sim.queueing_delay_percentile(from, to, 0.05)) as f64;
+    for (&f, &predicted) in flows.iter().zip(eq.rates.iter()) {
+        let rate = sim.throughput(f, from, to);
+        assert!((rate - predicted).abs() < 0.15 * predicted, "rate {} vs {}", rate, predicted);
+    }
+    assert!(
+        delay > 0.9 * eq.queueing_delay && delay < 1.1 * (eq.queueing_delay + eq.amplitude / 2.),
+        "delay {} vs {:?}",
+        delay,
+        eq
+    );
+    assert!(swing > 0.7 * eq.amplitude && swing < 1.6 * eq.amplitude, "swing {} vs {:?}", swing, eq);
+}
+
+#[test]
+fn equal_deltas() {
+    for &n in &[1, 2, 4] {
+        check(24., 40_000, &vec![0.5; n]);
+    }
+    check(96., 40_000, &[0.5, 0.5]);
+    check(24., 100_000, &[0.1, 0.1]);
+}
+
+#[test]
+fn mixed_deltas() {
+    check(24., 40_000, &[0.5, 0.25]);
+    check(96., 40_000, &[0.5, 0.25]);
+    check(12., 60_000, &[0.5, 0.25]);
+}
+
+#[test]
+fn small_windows_are_flagged() {
+    // Four flows on a path with a BDP of 20 packets can't go down to the
+    // windows the analysis asks of them
+    let eq = Equilibrium::new(1.5e6, 20_000, MSS, &[0.5; 4], 10 * MSS);
+    assert!(eq.window_limited);
+    let eq = Equilibrium::new(1.5e6, 20_000, MSS, &[0.5; 2], 2 * MSS);
+    assert!(!eq.window_limited);
+}
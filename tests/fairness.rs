@@ -0,0 +1,120 @@
+extern crate ccp_copa;
+extern crate portus;
+
+mod common;
+
+use ccp_copa::sim::{Bottleneck, Simulator};
+use ccp_copa::DeltaModeConf;
+use common::config;
+
+const RTT: u64 = 40_000;
+
+fn jain_index(rates: &[f64]) -> f64 {
+    let sum: f64 = rates.iter().sum();
+    sum * sum / (rates.len() as f64 * rates.iter().map(|r| r * r).sum::<f64>())
+}
+
+// Check that flows are fair in every second from `from` till `to` (in us)
+fn assert_fair(sim: &Simulator, flows: &[usize], from: u64, to: u64) {
+    let mut t = from;
+    while t + 1_000_000 <= to {
+        let rates: Vec<f64> = flows
+            .iter()
+            .map(|&f| sim.throughput(f, t, t + 1_000_000))
+            .collect();
+        let index = jain_index(&rates);
+        assert!(index > 0.95, "fairness {} at {} us, rates {:?}", index, t, rates);
+        t += 1_000_000;
+    }
+}
+
+// Flows that start half a second apart are fair within 200 RTTs of the last
+// one starting, and stay so. The first flow saw the path empty, whereas later
+// ones take some of the queue for base RTT, so the first takes a while to get
+// its share back.
+//
+// Flows in `DeltaModeConf::Auto` alone on a bottleneck can take each other for
+// buffer-filling flows once there are more than two of them, so larger groups
+// are checked with the default delta. See `auto_flows_converge` for those
+#[test]
+fn flows_converge() {
+    for &n in &[2, 4, 8] {
+        let mut sim = Simulator::new(Bottleneck::new(48., 1000), RTT);
+        let flows: Vec<_> = (0..n)
+            .map(|i| sim.add_flow(config(DeltaModeConf::NoTCP), i as u64 * 500_000))
+            .collect();
+        sim.run_until(30_000_000);
+        let last_start = (n as u64 - 1) * 500_000;
+        assert_fair(&sim, &flows, last_start + 200 * RTT, 30_000_000);
+    }
+
+    let mut sim = Simulator::new(Bottleneck::new(48., 1000), RTT);
+    let flows: Vec<_> = (0..2)
+        .map(|i| sim.add_flow(config(DeltaModeConf::Auto), i * 500_000))
+        .collect();
+    sim.run_until(30_000_000);
+    assert_fair(&sim, &flows, 500_000 + 100 * RTT, 30_000_000);
+}
+
+// Known failure. With more than two Copa flows the queue no longer nearly
+// empties every few RTTs, as the flows' oscillations fall out of step, so each
+// takes the others for TCP. Once some of them back off for TCP the queue grows
+// further and the rest follow, so flows flap between modes and are unfair
+#[test]
+#[ignore = "Auto flows detect each other as TCP once there are more than two"]
+fn auto_flows_converge() {
+    for &n in &[4, 8] {
+        let mut sim = Simulator::new(Bottleneck::new(48., 1000), RTT);
+        let flows: Vec<_> = (0..n)
+            .map(|i| sim.add_flow(config(DeltaModeConf::Auto), i as u64 * 500_000))
+            .collect();
+        sim.run_until(30_000_000);
+        let last_start = (n as u64 - 1) * 500_000;
+        assert_fair(&sim, &flows, last_start + 200 * RTT, 30_000_000);
+    }
+}
+
+#[test]
+fn late_joiner_gets_share() {
+    let mut sim = Simulator::new(Bottleneck::new(48., 1000), RTT);
+    let mut flows: Vec<_> = (0..4)
+        .map(|_| sim.add_flow(config(DeltaModeConf::NoTCP), 0))
+        .collect();
+    let late = sim.add_flow(config(DeltaModeConf::NoTCP), 15_000_000);
+    flows.push(late);
+    sim.run_until(30_000_000);
+    let from = 15_000_000 + 100 * RTT;
+    let rate = sim.throughput(late, from, 30_000_000);
+    assert!(rate > 0.8 * 6e6 / 5., "late joiner {}", rate);
+    assert_fair(&sim, &flows, from, 30_000_000);
+}
+
+// With TCP on the bottleneck, Copa flows get within 35% of their share in
+// buffers of up to five BDPs, however many TCP flows there are
+#[test]
+fn copa_holds_share_against_reno() {
+    for &buffer in &[50, 200, 400] {
+        for &num_reno in &[1, 2] {
+            let mut sim = Simulator::new(Bottleneck::new(24., buffer), RTT);
+            let copa: Vec<_> = (0..2)
+                .map(|_| sim.add_flow(config(DeltaModeConf::Auto), 0))
+                .collect();
+            for _ in 0..num_reno {
+                sim.add_reno_flow(0);
+            }
+            sim.run_until(40_000_000);
+            let share = 3e6 / (2 + num_reno) as f64;
+            for &f in copa.iter() {
+                let rate = sim.throughput(f, 10_000_000, 40_000_000);
+                assert!(
+                    rate > 0.65 * share && rate < 1.35 * share,
+                    "buffer {} reno flows {}: copa {} vs share {}",
+                    buffer,
+                    num_reno,
+                    rate,
+                    share
+                );
+            }
+        }
+    }
+}
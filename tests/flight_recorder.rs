@@ -0,0 +1,62 @@
+extern crate ccp_copa;
+extern crate portus;
+
+mod common;
+
+use std::fs;
+
+use ccp_copa::sim::SimDatapath;
+use ccp_copa::{Copa, CopaConfig, FlightRecorderConf, Measurement};
+use common::info;
+
+const CAPACITY: usize = 8;
+
+#[test]
+fn timeout_dumps_the_last_entries() {
+    let dir = std::env::temp_dir().join(format!("copa-flight-test-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let cfg = CopaConfig {
+        flight_recorder: Some(FlightRecorderConf {
+            capacity: CAPACITY,
+            dump_dir: Some(dir.clone()),
+        }),
+        ..Default::default()
+    };
+    let mut copa = Copa::new(&cfg, SimDatapath::new(7), info(7));
+
+    let mut now = 1_000_000;
+    for _ in 0..20 {
+        now += 20_000;
+        copa.on_measurement(Measurement {
+            acked: 10 * 1448,
+            rtt: 40_000,
+            min_rtt: 40_000,
+            now,
+            ..Default::default()
+        });
+    }
+    assert!(fs::read_dir(&dir).unwrap().next().is_none());
+
+    now += 20_000;
+    copa.on_measurement(Measurement {
+        was_timeout: true,
+        min_rtt: u32::MAX,
+        now,
+        ..Default::default()
+    });
+
+    let path = dir.join(format!("copa-flight-7-{}.txt", now));
+    let dump = fs::read_to_string(&path).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    let lines: Vec<&str> = dump.lines().collect();
+    assert_eq!(lines[0], "sock_id 7 anomaly Timeout");
+    // The timeout and the reports just before it, oldest first
+    let expected: Vec<String> = (0..CAPACITY as u64)
+        .rev()
+        .map(|i| format!("Entry {{ now: {},", now - i * 20_000))
+        .collect();
+    assert_eq!(lines.len(), 1 + CAPACITY, "{}", dump);
+    for (line, prefix) in lines[1..].iter().zip(expected.iter()) {
+        assert!(line.starts_with(prefix.as_str()), "{} vs {}", line, prefix);
+    }
+}
@@ -0,0 +1,75 @@
+extern crate ccp_copa;
+extern crate portus;
+
+mod common;
+
+use ccp_copa::sim::{Bottleneck, Simulator, MSS};
+use ccp_copa::{CopaConfig, DeltaModeConf, IdleRestartConf};
+use common::{config, LINK_RATE};
+
+const PAUSE: u64 = 10_000_000;
+// Two RTOs of 200 ms
+const IDLE: u64 = 500_000;
+const RESUME: u64 = PAUSE + IDLE;
+
+struct Run {
+    // Window before the pause, and once the first report after it is in. A
+    // flow that restarts is in slow start, which doubles the window along
+    // with that first report
+    before: u32,
+    after: u32,
+    // Throughput over the first 500 ms after the pause, and over 4 s
+    first: f64,
+    recovered: f64,
+}
+
+// A flow on a 24 Mbit/s, 40 ms path that has nothing to send for `IDLE`
+fn run(idle_restart: IdleRestartConf) -> Run {
+    let mut sim = Simulator::new(Bottleneck::new(24., 1000), 40_000);
+    let cfg = CopaConfig {
+        idle_restart,
+        ..config(DeltaModeConf::NoTCP)
+    };
+    let flow = sim.add_flow(cfg, 0);
+    sim.run_until(PAUSE);
+    let before = sim.datapath(flow).get("Cwnd").unwrap();
+    sim.pause(flow, RESUME);
+    // The first packets after the pause are acked one RTT later
+    sim.run_until(RESUME + 45_000);
+    let after = sim.datapath(flow).get("Cwnd").unwrap();
+    sim.run_until(RESUME + 4_000_000);
+    Run {
+        before,
+        after,
+        first: sim.throughput(flow, RESUME, RESUME + 500_000),
+        recovered: sim.throughput(flow, RESUME, RESUME + 4_000_000),
+    }
+}
+
+#[test]
+fn reset_restarts_from_the_initial_window() {
+    let r = run(IdleRestartConf::Reset);
+    assert!(r.before > 50 * MSS, "{}", r.before);
+    assert!(r.after <= 2 * 10 * MSS, "{} -> {}", r.before, r.after);
+    assert!(r.first < 0.85 * LINK_RATE, "{}", r.first);
+    assert!(r.recovered > 0.8 * LINK_RATE, "{}", r.recovered);
+}
+
+#[test]
+fn decay_halves_the_window_for_every_rto_idle() {
+    let r = run(IdleRestartConf::Decay);
+    assert!(r.before > 50 * MSS, "{}", r.before);
+    let expected = 2 * (r.before / 4);
+    assert!(r.after >= expected && r.after <= expected + 2 * MSS, "{} -> {}", r.before, r.after);
+    assert!(r.recovered > 0.8 * LINK_RATE, "{}", r.recovered);
+}
+
+#[test]
+fn keep_resumes_with_the_window_from_before() {
+    let r = run(IdleRestartConf::Keep);
+    assert!(r.before > 50 * MSS, "{}", r.before);
+    let diff = (r.after as f64 - r.before as f64).abs();
+    assert!(diff < 0.1 * r.before as f64, "{} -> {}", r.before, r.after);
+    assert!(r.first > 0.85 * LINK_RATE, "{}", r.first);
+    assert!(r.recovered > 0.9 * LINK_RATE, "{}", r.recovered);
+}
@@ -0,0 +1,62 @@
+extern crate ccp_copa;
+extern crate portus;
+
+mod common;
+
+use ccp_copa::sim::{Bottleneck, Simulator};
+use ccp_copa::{DeltaMode, DeltaModeConf, ModeTransition};
+use common::config;
+
+// Four flows on a 24 Mbit/s, 40 ms path, with the buffer shrunk to a few
+// packets during each of the given episodes
+fn run_episodes(episodes: &[(u64, u64)]) -> Vec<ModeTransition> {
+    let mut sim = Simulator::new(Bottleneck::new(24., 1000), 40_000);
+    let flow = sim.add_flow(config(DeltaModeConf::NoTCP), 0);
+    for _ in 1..4 {
+        sim.add_flow(config(DeltaModeConf::NoTCP), 0);
+    }
+    for &(start, end) in episodes {
+        sim.run_until(start);
+        sim.set_buffer(2);
+        sim.run_until(end);
+        sim.set_buffer(1000);
+    }
+    sim.run_until(episodes.last().unwrap().1 + 10_000_000);
+    let transitions = sim.mode_transitions(flow).to_vec();
+    transitions
+}
+
+#[test]
+fn congestive_loss_enters_and_leaves_loss_mode() {
+    let transitions = run_episodes(&[(5_000_000, 10_000_000)]);
+    assert_eq!(transitions.len(), 2, "{:?}", transitions);
+    let (enter, leave) = (&transitions[0], &transitions[1]);
+    assert_eq!((enter.from.clone(), enter.to.clone()), (DeltaMode::Default, DeltaMode::Loss));
+    assert!(enter.now > 5_000_000 && enter.now < 7_000_000, "{:?}", enter);
+    assert_eq!((leave.from.clone(), leave.to.clone()), (DeltaMode::Loss, DeltaMode::Default));
+    assert!(leave.now > 10_000_000 && leave.now < 15_000_000, "{:?}", leave);
+    // Delta came back down on schedule rather than jumping
+    assert_eq!(leave.delta, 0.5);
+}
+
+#[test]
+fn each_loss_episode_is_a_separate_transition() {
+    let transitions = run_episodes(&[(5_000_000, 10_000_000), (20_000_000, 25_000_000)]);
+    let entries: Vec<_> = transitions.iter().filter(|t| t.to == DeltaMode::Loss).collect();
+    assert_eq!(entries.len(), 2, "{:?}", transitions);
+    assert!(entries[0].now < 10_000_000);
+    assert!(entries[1].now > 20_000_000);
+    assert_eq!(transitions.last().unwrap().to, DeltaMode::Default);
+}
+
+#[test]
+fn random_loss_does_not_enter_loss_mode() {
+    let mut sim = Simulator::new(Bottleneck::new(24., 1000), 40_000);
+    let flow = sim.add_flow(config(DeltaModeConf::NoTCP), 0);
+    sim.run_until(5_000_000);
+    sim.set_loss_rate(0.15);
+    sim.run_until(10_000_000);
+    sim.set_loss_rate(0.);
+    sim.run_until(20_000_000);
+    assert!(sim.mode_transitions(flow).is_empty(), "{:?}", sim.mode_transitions(flow));
+}
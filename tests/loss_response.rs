@@ -0,0 +1,111 @@
+extern crate ccp_copa;
+extern crate portus;
+
+mod common;
+
+use ccp_copa::sim::{Bottleneck, Simulator};
+use ccp_copa::{CopaConfig, DeltaModeConf, LossResponseConf};
+use common::config;
+
+const PROP_RTT: u64 = 40_000;
+const BETA: f32 = 0.7;
+const STEP: u64 = 1_000;
+
+fn start(loss_response: LossResponseConf) -> (Simulator, usize) {
+    let mut sim = Simulator::new(Bottleneck::new(24., 1000), PROP_RTT);
+    let cfg = CopaConfig {
+        loss_response,
+        ..config(DeltaModeConf::NoTCP)
+    };
+    let flow = sim.add_flow(cfg, 0);
+    sim.run_until(5_000_000);
+    (sim, flow)
+}
+
+// Windows the flow wrote from now till `end`, with when it wrote them
+fn run(sim: &mut Simulator, flow: usize, end: u64) -> Vec<(u64, u32)> {
+    let mut cwnds = Vec::new();
+    let mut num_updates = sim.datapath(flow).num_updates("Cwnd");
+    while sim.now() < end {
+        sim.run_until(sim.now() + STEP);
+        let dp = sim.datapath(flow);
+        if dp.num_updates("Cwnd") != num_updates {
+            num_updates = dp.num_updates("Cwnd");
+            cwnds.push((sim.now(), dp.get("Cwnd").unwrap()));
+        }
+    }
+    cwnds
+}
+
+// Lose packets for 10 ms, and return the window before the losses could have
+// been noticed, a round trip later, along with those written from then on
+fn burst(sim: &mut Simulator, flow: usize) -> (u32, Vec<(u64, u32)>) {
+    let start = sim.now();
+    sim.set_loss_rate(0.3);
+    let mut cwnds = run(sim, flow, start + 10_000);
+    sim.set_loss_rate(0.);
+    cwnds.extend(run(sim, flow, start + 300_000));
+    let i = cwnds.iter().position(|&(t, _)| t >= start + PROP_RTT).unwrap();
+    (cwnds[i - 1].1, cwnds.split_off(i))
+}
+
+// Cuts of more than delay control would make, with how much they cut by
+fn cuts(prev: u32, cwnds: &[(u64, u32)]) -> Vec<(u64, f32)> {
+    let mut prev = prev;
+    let mut cuts = Vec::new();
+    for &(t, cwnd) in cwnds {
+        let ratio = cwnd as f32 / prev as f32;
+        if ratio < 0.9 {
+            cuts.push((t, ratio));
+        }
+        prev = cwnd;
+    }
+    cuts
+}
+
+#[test]
+fn decrease_cuts_once_by_beta() {
+    let (mut sim, flow) = start(LossResponseConf::Decrease { beta: BETA });
+    for _ in 0..4 {
+        // The losses of a burst are reported over several reports
+        let (before, cwnds) = burst(&mut sim, flow);
+        let cuts = cuts(before, &cwnds);
+        assert_eq!(cuts.len(), 1, "{} then {:?}", before, cwnds);
+        // Delay control moves the window a little along with the cut
+        assert!((cuts[0].1 - BETA).abs() < 0.03, "{:?}", cuts);
+        sim.run_until(sim.now() + 700_000);
+    }
+}
+
+#[test]
+fn decrease_cuts_at_most_once_per_rtt() {
+    let (mut sim, flow) = start(LossResponseConf::Decrease { beta: BETA });
+    sim.set_loss_rate(0.02);
+    let cwnds = run(&mut sim, flow, 10_000_000);
+    let cuts = cuts(cwnds[0].1, &cwnds[1..]);
+    assert!(cuts.len() > 20, "{:?}", cuts);
+    for pair in cuts.windows(2) {
+        assert!(pair[1].0 - pair[0].0 >= PROP_RTT, "{:?}", pair);
+    }
+}
+
+#[test]
+fn prr_brings_the_window_down_over_an_rtt() {
+    let (mut sim, flow) = start(LossResponseConf::Prr { beta: BETA });
+    for _ in 0..4 {
+        let (before, cwnds) = burst(&mut sim, flow);
+        let ssthresh = (before as f32 * BETA) as u32;
+        let end = cwnds.iter().position(|&(_, cwnd)| cwnd == ssthresh);
+        let end = end.unwrap_or_else(|| panic!("{} then {:?}", before, cwnds));
+        let (first, last) = (cwnds[0].0, cwnds[end].0);
+
+        // In steps, never below where it is headed, and within about a round
+        // trip of the first report of loss. Reports come twice per RTT, so
+        // they may have it take a little longer
+        assert!(cwnds[..end].iter().all(|&(_, cwnd)| cwnd > ssthresh), "{} then {:?}", before, cwnds);
+        let num_steps = cwnds[..end].iter().filter(|&&(_, cwnd)| cwnd < before).count();
+        assert!(num_steps >= 2, "{} then {:?}", before, cwnds);
+        assert!(last - first <= 2 * PROP_RTT, "{} then {:?}", before, cwnds);
+        sim.run_until(sim.now() + 700_000);
+    }
+}
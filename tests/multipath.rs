@@ -0,0 +1,47 @@
+extern crate ccp_copa;
+extern crate portus;
+
+mod common;
+
+use ccp_copa::sim::{Bottleneck, Simulator};
+use ccp_copa::{Aggregator, CopaConfig, DeltaModeConf, MultipathGroups};
+use common::{config, LINK_RATE};
+
+// With all subflows on one bottleneck, the connection takes one flow's share
+#[test]
+fn coupled_subflows_take_one_share() {
+    for &n in &[2, 3, 4] {
+        let mut sim = Simulator::new(Bottleneck::new(24., 1000), 40_000);
+        // Only flows given the groups are subflows of the connection
+        let cfg = CopaConfig {
+            multipath: Some(MultipathGroups::new(|_| 0)),
+            ..config(DeltaModeConf::NoTCP)
+        };
+        let subflows: Vec<_> = (0..n).map(|_| sim.add_flow(cfg.clone(), 0)).collect();
+        let other = sim.add_flow(config(DeltaModeConf::NoTCP), 0);
+        sim.run_until(30_000_000);
+        let total: f64 = subflows
+            .iter()
+            .map(|&flow| sim.throughput(flow, 10_000_000, 30_000_000))
+            .sum();
+        let t_other = sim.throughput(other, 10_000_000, 30_000_000);
+        assert!(total + t_other > 0.9 * LINK_RATE, "{}: {} + {}", n, total, t_other);
+        let ratio = total / t_other;
+        assert!(ratio > 0.8 && ratio < 1.25, "{}: connection {} against {}", n, total, t_other);
+    }
+}
+
+#[test]
+#[should_panic(expected = "both aggregated and coupled")]
+fn aggregation_and_multipath_are_rejected() {
+    let mut sim = Simulator::new(Bottleneck::new(24., 1000), 40_000);
+    sim.add_flow(
+        CopaConfig {
+            aggregator: Some(Aggregator::new(|_| 0)),
+            multipath: Some(MultipathGroups::new(|_| 0)),
+            ..config(DeltaModeConf::NoTCP)
+        },
+        0,
+    );
+    sim.run_until(1_000);
+}
@@ -0,0 +1,132 @@
+extern crate ccp_copa;
+extern crate portus;
+
+mod common;
+
+use std::net::Ipv4Addr;
+use std::thread;
+use std::time::Duration;
+
+use ccp_copa::sim::{SimDatapath, MSS};
+use ccp_copa::{Copa, CopaConfig, Measurement, PathCache};
+use portus::DatapathInfo;
+
+use common::info;
+
+const INIT_CWND: u32 = 10 * MSS;
+const RTT: u32 = 50_000;
+
+fn start(cache: &PathCache, sock_id: u32, dst: Ipv4Addr) -> (Copa<SimDatapath>, SimDatapath) {
+    let cfg = CopaConfig {
+        path_cache: Some(cache.clone()),
+        ..Default::default()
+    };
+    let info = DatapathInfo {
+        dst_ip: u32::from(dst).to_be(),
+        dst_port: 443,
+        ..info(sock_id)
+    };
+    let dp = SimDatapath::new(sock_id);
+    (Copa::new(&cfg, dp.clone(), info), dp)
+}
+
+// Run a flow for a while on an uncongested path, so that it records a base
+// RTT and a window well above the initial one
+fn run(copa: &mut Copa<SimDatapath>) {
+    let mut now = 1_000_000;
+    for _ in 0..50 {
+        now += (RTT / 2) as u64;
+        copa.on_measurement(Measurement {
+            acked: 10 * 1448,
+            rtt: RTT,
+            min_rtt: RTT,
+            now,
+            ..Default::default()
+        });
+    }
+}
+
+// Base RTT the flow paces by, from the rate and window it wrote
+fn pacing_base_rtt(dp: &SimDatapath) -> u32 {
+    let (cwnd, rate) = (dp.get("Cwnd").unwrap(), dp.get("Rate").unwrap());
+    (2 * cwnd as u64 * 1_000_000 / rate as u64) as u32
+}
+
+#[test]
+fn second_flow_to_the_same_prefix_starts_from_the_cache() {
+    let cache = PathCache::new(24, Duration::from_secs(60));
+    let (mut first, first_dp) = start(&cache, 1, Ipv4Addr::new(10, 0, 0, 1));
+    run(&mut first);
+    let cwnd = first_dp.get("Cwnd").unwrap();
+    assert!(cwnd > 4 * INIT_CWND, "{}", cwnd);
+    let key = cache.key(u32::from(Ipv4Addr::new(10, 0, 0, 1)).to_be());
+    assert_eq!(cache.lookup(key), Some((RTT, cwnd)));
+
+    // Half the window, since it may have been a share of a busier bottleneck
+    let (_second, dp) = start(&cache, 2, Ipv4Addr::new(10, 0, 0, 200));
+    assert_eq!(dp.get("Cwnd"), Some(cwnd / 2));
+    let base_rtt = pacing_base_rtt(&dp);
+    assert!(base_rtt.abs_diff(RTT) <= 1, "{}", base_rtt);
+
+    let (_other, dp) = start(&cache, 3, Ipv4Addr::new(10, 0, 1, 1));
+    assert_eq!(dp.get("Cwnd"), Some(INIT_CWND));
+}
+
+#[test]
+fn prefix_length_is_honoured() {
+    let key = |cache: &PathCache, a, b, c, d| cache.key(u32::from(Ipv4Addr::new(a, b, c, d)).to_be());
+    let cache = PathCache::new(16, Duration::from_secs(60));
+    assert_eq!(key(&cache, 10, 1, 2, 3), key(&cache, 10, 1, 200, 4));
+    assert_ne!(key(&cache, 10, 1, 2, 3), key(&cache, 10, 2, 2, 3));
+
+    let cache = PathCache::new(32, Duration::from_secs(60));
+    assert_ne!(key(&cache, 10, 1, 2, 3), key(&cache, 10, 1, 2, 4));
+
+    let cache = PathCache::new(0, Duration::from_secs(60));
+    assert_eq!(key(&cache, 10, 1, 2, 3), key(&cache, 192, 168, 0, 1));
+
+    // A flow to another /16 starts afresh
+    let cache = PathCache::new(16, Duration::from_secs(60));
+    let (mut first, _) = start(&cache, 1, Ipv4Addr::new(10, 1, 0, 1));
+    run(&mut first);
+    let (_, dp) = start(&cache, 2, Ipv4Addr::new(10, 1, 99, 1));
+    assert!(dp.get("Cwnd").unwrap() > INIT_CWND);
+    let (_, dp) = start(&cache, 3, Ipv4Addr::new(10, 2, 0, 1));
+    assert_eq!(dp.get("Cwnd"), Some(INIT_CWND));
+}
+
+#[test]
+fn entries_expire_after_the_ttl() {
+    let cache = PathCache::new(24, Duration::from_millis(200));
+    let (mut first, _) = start(&cache, 1, Ipv4Addr::new(10, 0, 0, 1));
+    run(&mut first);
+    let (_, dp) = start(&cache, 2, Ipv4Addr::new(10, 0, 0, 2));
+    assert!(dp.get("Cwnd").unwrap() > INIT_CWND);
+
+    thread::sleep(Duration::from_millis(300));
+    let (_, dp) = start(&cache, 3, Ipv4Addr::new(10, 0, 0, 3));
+    assert_eq!(dp.get("Cwnd"), Some(INIT_CWND));
+}
+
+#[test]
+fn expired_entries_are_swept_when_recording() {
+    let cache = PathCache::new(32, Duration::from_millis(200));
+    for key in 0..100 {
+        cache.record(key, RTT, INIT_CWND);
+    }
+    assert_eq!(cache.len(), 100);
+    thread::sleep(Duration::from_millis(300));
+    cache.record(1000, RTT, INIT_CWND);
+    assert_eq!(cache.len(), 1);
+}
+
+#[test]
+fn size_is_capped() {
+    let cache = PathCache::new(32, Duration::from_secs(60));
+    for key in 0..70_000 {
+        cache.record(key, RTT, INIT_CWND);
+    }
+    assert!(cache.len() <= 65_536 && cache.len() > 60_000, "{}", cache.len());
+    // The latest entries are kept
+    assert_eq!(cache.lookup(69_999), Some((RTT, INIT_CWND)));
+}
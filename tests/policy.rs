@@ -0,0 +1,131 @@
+extern crate ccp_copa;
+extern crate portus;
+
+mod common;
+
+use ccp_copa::sim::{Bottleneck, Simulator};
+use ccp_copa::{CopaConfig, DeltaModeConf, FlowMatch, FlowPolicy, PolicyTable};
+use portus::DatapathInfo;
+
+use common::LINK_RATE;
+
+fn config(policies: PolicyTable) -> CopaConfig {
+    CopaConfig {
+        policies: Some(policies),
+        ..common::config(DeltaModeConf::NoTCP)
+    }
+}
+
+// Flows in the simulator have their index as socket id
+fn sock(sock_id: u32) -> FlowMatch {
+    FlowMatch {
+        sock_id: Some(sock_id),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn max_rate_caps_flow() {
+    let policies = PolicyTable::new();
+    policies.set(
+        sock(1),
+        FlowPolicy {
+            max_rate: Some(500_000),
+            ..Default::default()
+        },
+    );
+    let mut sim = Simulator::new(Bottleneck::new(24., 1000), 40_000);
+    let fg = sim.add_flow(config(policies.clone()), 0);
+    let bg = sim.add_flow(config(policies.clone()), 0);
+    sim.run_until(30_000_000);
+    let (fg_rate, bg_rate) = (
+        sim.throughput(fg, 10_000_000, 30_000_000),
+        sim.throughput(bg, 10_000_000, 30_000_000),
+    );
+    assert!(bg_rate < 1.05 * 500_000., "background {}", bg_rate);
+    assert!(fg_rate > 0.85 * (LINK_RATE - 500_000.), "foreground {}", fg_rate);
+
+    // Lifting the cap lets the flow take its fair share again
+    policies.remove(&sock(1));
+    sim.run_until(60_000_000);
+    let (fg_rate, bg_rate) = (
+        sim.throughput(fg, 40_000_000, 60_000_000),
+        sim.throughput(bg, 40_000_000, 60_000_000),
+    );
+    assert!(bg_rate > 0.3 * LINK_RATE, "background {}", bg_rate);
+    assert!(fg_rate > 0.3 * LINK_RATE, "foreground {}", fg_rate);
+}
+
+#[test]
+fn max_cwnd_caps_flow() {
+    let policies = PolicyTable::new();
+    policies.set(
+        sock(0),
+        FlowPolicy {
+            max_cwnd: Some(10 * 1448),
+            ..Default::default()
+        },
+    );
+    let mut sim = Simulator::new(Bottleneck::new(24., 1000), 40_000);
+    let flow = sim.add_flow(config(policies), 0);
+    sim.run_until(20_000_000);
+    // At most 10 packets per 40ms round trip
+    let rate = sim.throughput(flow, 5_000_000, 20_000_000);
+    assert!(rate < 1.05 * 10. * 1448. / 0.04, "rate {}", rate);
+    assert!(sim.datapath(flow).get("Cwnd").unwrap() <= 10 * 1448);
+}
+
+#[test]
+fn min_rate_holds_against_competition() {
+    let policies = PolicyTable::new();
+    policies.set(
+        sock(0),
+        FlowPolicy {
+            min_rate: Some(1_500_000),
+            ..Default::default()
+        },
+    );
+    let mut sim = Simulator::new(Bottleneck::new(24., 1000), 40_000);
+    let flows: Vec<_> = (0..4)
+        .map(|_| sim.add_flow(config(policies.clone()), 0))
+        .collect();
+    sim.run_until(30_000_000);
+    let rate = sim.throughput(flows[0], 10_000_000, 30_000_000);
+    assert!(rate > 0.95 * 1_500_000., "rate {}", rate);
+}
+
+#[test]
+fn policy_file() {
+    let policies = PolicyTable::parse(
+        "# Backups\n\
+         dst_ip=10.0.0.2 dst_port=873 max_rate=1000000\n\
+         \n\
+         sock_id=7 min_rate=20000 max_cwnd=14480\n",
+    )
+    .unwrap();
+    let info = |sock_id, dst_ip: [u8; 4], dst_port: u16| DatapathInfo {
+        dst_ip: u32::from_be_bytes(dst_ip).to_be(),
+        dst_port: dst_port.to_be() as u32,
+        ..common::info(sock_id)
+    };
+    assert_eq!(
+        policies.lookup(&info(1, [10, 0, 0, 2], 873)),
+        Some(FlowPolicy {
+            max_rate: Some(1_000_000),
+            ..Default::default()
+        })
+    );
+    assert_eq!(policies.lookup(&info(1, [10, 0, 0, 3], 873)), None);
+    assert_eq!(
+        policies.lookup(&info(7, [10, 0, 0, 3], 443)),
+        Some(FlowPolicy {
+            min_rate: Some(20_000),
+            max_rate: None,
+            max_cwnd: Some(14_480),
+        })
+    );
+
+    assert!(PolicyTable::parse("dst_port=http max_rate=1").is_err());
+    assert!(PolicyTable::parse("min_rate=10 max_rate=5").is_err());
+    assert!(PolicyTable::parse("rate=5").is_err());
+}
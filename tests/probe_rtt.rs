@@ -0,0 +1,95 @@
+extern crate ccp_copa;
+extern crate portus;
+
+mod common;
+
+use ccp_copa::sim::{Bottleneck, Simulator, MSS};
+use ccp_copa::{CopaConfig, DeltaModeConf, ProbeRttConf};
+use common::config;
+
+const PROP_RTT: u64 = 40_000;
+const INTERVAL: u64 = 10_000_000;
+const DURATION: u64 = 200_000;
+const PROBE_CWND: u32 = 4;
+const STEP: u64 = 10_000;
+
+// Base RTT the flow paces by, from the rate and window it wrote
+fn pacing_base_rtt(sim: &Simulator, flow: usize) -> u64 {
+    let dp = sim.datapath(flow);
+    let (cwnd, rate) = (dp.get("Cwnd").unwrap(), dp.get("Rate").unwrap());
+    2 * cwnd as u64 * 1_000_000 / rate as u64
+}
+
+// What a flow did around its first probe
+#[derive(Debug, Default)]
+struct Probe {
+    base_rtt_before: u64,
+    cwnd_before: u32,
+    start: u64,
+    end: u64,
+    cwnd_after: u32,
+}
+
+// Enough flows, started one after the other, that the queue never drains and
+// later flows take much of it for base RTT
+#[test]
+fn probe_drains_the_queue_and_refreshes_base_rtt() {
+    let mut sim = Simulator::new(Bottleneck::new(12., 1000), PROP_RTT);
+    let cfg = CopaConfig {
+        probe_rtt: Some(ProbeRttConf {
+            interval: INTERVAL,
+            duration: DURATION,
+            cwnd: PROBE_CWND,
+        }),
+        ..config(DeltaModeConf::NoTCP)
+    };
+    let flows: Vec<usize> = (0..8).map(|i| sim.add_flow(cfg.clone(), i * 500_000)).collect();
+    sim.run_until(INTERVAL - 500_000);
+    assert!(sim.queueing_delay(INTERVAL - 1_000_000, INTERVAL - 500_000) > 50_000.);
+
+    // Flows probe together, at the start of every interval
+    let mut probes: Vec<Probe> = flows.iter().map(|_| Probe::default()).collect();
+    while sim.now() < INTERVAL + 1_000_000 {
+        sim.run_until(sim.now() + STEP);
+        for (&flow, p) in flows.iter().zip(probes.iter_mut()) {
+            let cwnd = sim.datapath(flow).get("Cwnd").unwrap();
+            if cwnd <= PROBE_CWND * MSS {
+                assert!(sim.now() >= INTERVAL, "flow {} capped at {}", flow, sim.now());
+                assert_eq!(cwnd, PROBE_CWND * MSS);
+                if p.start == 0 {
+                    p.start = sim.now();
+                }
+                p.end = sim.now();
+            } else if p.start == 0 {
+                p.base_rtt_before = pacing_base_rtt(&sim, flow);
+                p.cwnd_before = cwnd;
+            } else if p.cwnd_after == 0 {
+                p.cwnd_after = cwnd;
+            }
+        }
+    }
+
+    assert!(probes.iter().any(|p| p.base_rtt_before > 2 * PROP_RTT), "{:?}", probes);
+    for (&flow, p) in flows.iter().zip(probes.iter()) {
+        // Probes last at least `DURATION`, and two base RTTs on longer paths.
+        // They end with the first report after that, and reports come twice
+        // per base RTT
+        let duration = p.end + STEP - p.start;
+        let expected = std::cmp::max(DURATION, 2 * p.base_rtt_before);
+        let slack = p.base_rtt_before / 2 + 2 * STEP;
+        assert!(
+            duration + STEP >= expected && duration <= expected + slack,
+            "flow {} probed for {} us: {:?}",
+            flow,
+            duration,
+            p
+        );
+        // The window is where the probe left it, since the empty queue was not
+        // taken as room to grow
+        let diff = (p.cwnd_after as f64 - p.cwnd_before as f64).abs();
+        assert!(diff <= (2 * MSS) as f64 + 0.1 * p.cwnd_before as f64, "flow {}: {:?}", flow, p);
+        // And base RTT is that of the empty path
+        let base_rtt = pacing_base_rtt(&sim, flow);
+        assert!((PROP_RTT..PROP_RTT + 5_000).contains(&base_rtt), "flow {}: {}", flow, base_rtt);
+    }
+}
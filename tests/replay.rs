@@ -0,0 +1,105 @@
+extern crate ccp_copa;
+#[macro_use]
+extern crate slog;
+
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+use ccp_copa::recorder::{self, Recorder, SharedBuf};
+use ccp_copa::sim::{Bottleneck, Simulator};
+use ccp_copa::CopaConfig;
+
+fn record_run() -> Vec<u8> {
+    let buf = SharedBuf::default();
+    let mut cfg = CopaConfig {
+        ..Default::default()
+    };
+    cfg.recorder = Some(Recorder::new(buf.clone(), &cfg).unwrap());
+
+    // A small buffer, so that there are losses too
+    let mut sim = Simulator::new(Bottleneck::new(12., 60), 30_000);
+    sim.add_flow(cfg.clone(), 0);
+    sim.add_flow(cfg, 2_000_000);
+    sim.run_until(6_000_000);
+
+    buf.contents()
+}
+
+#[test]
+fn replay_is_identical() {
+    let recording = record_run();
+    let summary = recorder::replay(&recording[..]).unwrap();
+    assert_eq!(summary.num_flows, 2);
+    assert!(summary.num_measurements > 100);
+    assert!(summary.num_updates > summary.num_measurements);
+    assert!(!summary.shared);
+    assert!(summary.divergence.is_none(), "{:?}", summary.divergence);
+}
+
+#[test]
+fn replay_detects_divergence() {
+    let mut recording = record_run();
+    // Change the value of the last update, which is followed only by the
+    // flow's state, 23 bytes long
+    let n = recording.len() - 23;
+    recording[n - 1] ^= 1;
+    let summary = recorder::replay(&recording[..]).unwrap();
+    let d = summary.divergence.expect("replay should diverge");
+    assert_eq!(d.index, summary.num_updates - 1);
+    assert!(d.expected != d.got);
+}
+
+// Takes the header of a recording, then fails every write, counting them
+#[derive(Clone, Default)]
+struct Failing(Arc<Mutex<u32>>);
+
+impl Write for Failing {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut num_writes = self.0.lock().unwrap();
+        *num_writes += 1;
+        match *num_writes {
+            1 => Ok(buf.len()),
+            _ => Err(io::Error::new(io::ErrorKind::Other, "disk full")),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Keeps the messages of warnings
+struct Warnings(Arc<Mutex<Vec<String>>>);
+
+impl slog::Drain for Warnings {
+    type Ok = ();
+    type Err = slog::Never;
+
+    fn log(&self, record: &slog::Record, _: &slog::OwnedKVList) -> Result<(), slog::Never> {
+        if record.level() == slog::Level::Warning {
+            self.0.lock().unwrap().push(record.msg().to_string());
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn failed_write_stops_recording_with_one_warning() {
+    let warnings = Arc::new(Mutex::new(Vec::new()));
+    let writer = Failing::default();
+    let mut cfg = CopaConfig {
+        logger: Some(slog::Logger::root(Warnings(warnings.clone()), o!())),
+        ..Default::default()
+    };
+    cfg.recorder = Some(Recorder::new(writer.clone(), &cfg).unwrap());
+    let mut sim = Simulator::new(Bottleneck::new(12., 60), 30_000);
+    sim.add_flow(cfg.clone(), 0);
+    sim.add_flow(cfg, 0);
+    sim.run_until(2_000_000);
+
+    // The header, and the first record
+    assert_eq!(*writer.0.lock().unwrap(), 2);
+    let warnings = warnings.lock().unwrap();
+    let stopped: Vec<_> = warnings.iter().filter(|w| w.contains("recording")).collect();
+    assert_eq!(stopped.len(), 1, "{:?}", warnings);
+}
@@ -0,0 +1,64 @@
+extern crate ccp_copa;
+extern crate portus;
+
+mod common;
+
+use ccp_copa::sim::{Bottleneck, Simulator};
+use ccp_copa::DeltaModeConf;
+use common::{config, LINK_RATE};
+
+const SCAVENGER: DeltaModeConf = DeltaModeConf::Scavenger { target: 5_000 };
+
+#[test]
+fn scavenger_alone_uses_link() {
+    let mut sim = Simulator::new(Bottleneck::new(24., 1000), 40_000);
+    let flow = sim.add_flow(config(SCAVENGER), 0);
+    sim.run_until(30_000_000);
+    let rate = sim.throughput(flow, 10_000_000, 30_000_000);
+    assert!(rate > 0.9 * LINK_RATE, "rate {}", rate);
+}
+
+#[test]
+fn scavenger_yields_to_copa() {
+    let mut sim = Simulator::new(Bottleneck::new(24., 1000), 40_000);
+    let scavenger = sim.add_flow(config(SCAVENGER), 0);
+    let copa = sim.add_flow(config(DeltaModeConf::NoTCP), 10_000_000);
+    sim.run_until(40_000_000);
+    let (scavenger, copa) = (
+        sim.throughput(scavenger, 20_000_000, 40_000_000),
+        sim.throughput(copa, 20_000_000, 40_000_000),
+    );
+    assert!(copa > 0.9 * LINK_RATE, "copa {}", copa);
+    assert!(scavenger < 0.1 * LINK_RATE, "scavenger {}", scavenger);
+    assert!(scavenger + copa > 0.9 * LINK_RATE, "total {}", scavenger + copa);
+}
+
+#[test]
+fn scavenger_yields_to_buffer_filler() {
+    let mut sim = Simulator::new(Bottleneck::new(24., 200), 40_000);
+    let scavenger = sim.add_flow(config(SCAVENGER), 0);
+    let bbr = sim.add_bbr_flow(10_000_000);
+    sim.run_until(40_000_000);
+    let (scavenger, bbr) = (
+        sim.throughput(scavenger, 20_000_000, 40_000_000),
+        sim.throughput(bbr, 20_000_000, 40_000_000),
+    );
+    assert!(bbr > 0.9 * LINK_RATE, "bbr {}", bbr);
+    assert!(scavenger < 0.1 * LINK_RATE, "scavenger {}", scavenger);
+}
+
+#[test]
+fn scavenger_yields_to_reno() {
+    let mut sim = Simulator::new(Bottleneck::new(24., 200), 40_000);
+    let scavenger = sim.add_flow(config(SCAVENGER), 0);
+    let reno = sim.add_reno_flow(10_000_000);
+    sim.run_until(40_000_000);
+    let (scavenger, reno) = (
+        sim.throughput(scavenger, 20_000_000, 40_000_000),
+        sim.throughput(reno, 20_000_000, 40_000_000),
+    );
+    // Reno keeps the buffer full, so the scavenger should sit at its minimum
+    // window and Reno should get the link as if it were alone
+    assert!(reno > 0.95 * LINK_RATE, "reno {}", reno);
+    assert!(scavenger / (scavenger + reno) < 0.05, "scavenger {} reno {}", scavenger, reno);
+}
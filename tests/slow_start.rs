@@ -0,0 +1,88 @@
+extern crate ccp_copa;
+extern crate portus;
+
+mod common;
+
+use ccp_copa::sim::{Bottleneck, Simulator, MSS};
+use ccp_copa::{CopaConfig, DeltaModeConf, SlowStartConf};
+use common::config;
+
+const STEP: u64 = 5_000;
+
+struct Run {
+    // When the flow left slow start, and the window then
+    exit: u64,
+    exit_cwnd: u32,
+    // Most the window grew by over any one propagation RTT in slow start
+    growth: f64,
+    // Most queueing delay over the first 3 s
+    max_queue: u64,
+}
+
+// A flow alone on a path, with a buffer deep enough that slow start never loses
+// packets
+fn run(slow_start: SlowStartConf, rate_mbps: f64, prop_rtt: u64) -> Run {
+    let mut sim = Simulator::new(Bottleneck::new(rate_mbps, 2000), prop_rtt);
+    let cfg = CopaConfig {
+        slow_start,
+        ..config(DeltaModeConf::NoTCP)
+    };
+    let flow = sim.add_flow(cfg, 0);
+    let mut cwnds = Vec::new();
+    while sim.now() == 0 || sim.in_slow_start(flow) {
+        sim.run_until(sim.now() + STEP);
+        cwnds.push(sim.datapath(flow).get("Cwnd").unwrap_or(10 * MSS));
+    }
+    let exit = sim.now();
+    sim.run_until(3_000_000);
+
+    let k = (prop_rtt / STEP) as usize;
+    let growth = (k..cwnds.len())
+        .map(|i| cwnds[i] as f64 / cwnds[i - k] as f64)
+        .fold(0., f64::max);
+    Run {
+        exit,
+        exit_cwnd: *cwnds.last().unwrap(),
+        growth,
+        max_queue: sim.queueing_delay_percentile(0, 3_000_000, 1.),
+    }
+}
+
+fn hystart(rounds: u32, max_growth: f32) -> SlowStartConf {
+    SlowStartConf::HyStart { rounds, max_growth }
+}
+
+#[test]
+fn hystart_grows_at_most_max_growth_per_rtt() {
+    for &max_growth in &[1.25, 1.5] {
+        let r = run(hystart(2, max_growth), 48., 100_000);
+        assert!(r.growth <= max_growth as f64 + 0.01, "{} grew by {}", max_growth, r.growth);
+        assert!(r.growth > max_growth as f64 - 0.05, "{} grew by {}", max_growth, r.growth);
+    }
+    // Whereas classic slow start at least doubles the window every RTT
+    let r = run(SlowStartConf::Classic, 48., 100_000);
+    assert!(r.growth >= 2., "{}", r.growth);
+}
+
+// Acks stop arriving faster as soon as the bottleneck is full, so the delay
+// rounds only tell when the window grows fast enough for reports to lag
+#[test]
+fn hystart_waits_for_rounds() {
+    let exits: Vec<u64> = [1, 2, 4].iter().map(|&rounds| run(hystart(rounds, 2.), 100., 20_000).exit).collect();
+    assert!(exits[0] < exits[1] && exits[1] < exits[2], "{:?}", exits);
+    assert!(exits[2] - exits[0] >= 2 * 20_000, "{:?}", exits);
+}
+
+// Classic slow start leaves at the first report that says the window should
+// not grow, which comes a round after the window has already overshot
+#[test]
+fn hystart_exits_later_with_less_overshoot_than_classic() {
+    for &(rate_mbps, prop_rtt) in &[(24., 40_000), (48., 100_000), (12., 200_000), (100., 20_000)] {
+        let classic = run(SlowStartConf::Classic, rate_mbps, prop_rtt);
+        let hystart = run(hystart(2, 1.5), rate_mbps, prop_rtt);
+        let path = format!("{} Mbit/s, {} us", rate_mbps, prop_rtt);
+        assert!(hystart.exit > classic.exit, "{}: {} vs {}", path, hystart.exit, classic.exit);
+        assert!(hystart.exit_cwnd < classic.exit_cwnd, "{}: {} vs {}", path, hystart.exit_cwnd, classic.exit_cwnd);
+        assert!(hystart.max_queue < classic.max_queue, "{}: {} vs {}", path, hystart.max_queue, classic.max_queue);
+    }
+}
@@ -0,0 +1,41 @@
+extern crate ccp_copa;
+extern crate portus;
+
+mod common;
+
+use ccp_copa::sim::{Bottleneck, Simulator};
+use ccp_copa::DeltaModeConf;
+use common::config;
+
+// Average queueing delay, and total throughput in bytes per second, of flows
+// aiming for the given target
+fn run(num_flows: usize, target: u32) -> (f64, f64) {
+    let mut sim = Simulator::new(Bottleneck::new(24., 1000), 40_000);
+    let flows: Vec<_> = (0..num_flows)
+        .map(|_| sim.add_flow(config(DeltaModeConf::TargetDelay { target }), 0))
+        .collect();
+    sim.run_until(40_000_000);
+    let delay = sim.queueing_delay(20_000_000, 40_000_000);
+    let throughput = flows
+        .iter()
+        .map(|&f| sim.throughput(f, 20_000_000, 40_000_000))
+        .sum();
+    (delay, throughput)
+}
+
+#[test]
+fn queueing_delay_follows_target() {
+    // The target is for the queue at its shortest, which the average exceeds
+    for &num_flows in &[1, 3] {
+        let (short, _) = run(num_flows, 5_000);
+        let (long, _) = run(num_flows, 20_000);
+        assert!(short > 5_000. && short < 10_000., "delay {}", short);
+        assert!(long > 20_000. && long < 40_000., "delay {}", long);
+    }
+}
+
+#[test]
+fn short_target_keeps_link_busy() {
+    let (_, throughput) = run(2, 2_000);
+    assert!(throughput > 0.95 * 24e6 / 8., "throughput {}", throughput);
+}
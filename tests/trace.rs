@@ -0,0 +1,103 @@
+extern crate ccp_copa;
+extern crate portus;
+
+mod common;
+
+use ccp_copa::sim::{AqmConf, Bottleneck, Simulator, Trace};
+use ccp_copa::DeltaModeConf;
+use common::config;
+
+// Fixtures in tests/traces are synthetic: a constant 12 Mbit/s link, one that
+// steps between 12 and 48 Mbit/s every second, and a cellular-like one whose
+// rate wanders between 3 and 60 Mbit/s every 50 ms with two outages
+fn trace(name: &str) -> Trace {
+    Trace::from_file(format!("tests/traces/{}.trace", name)).unwrap()
+}
+
+const END: u64 = 30_000_000;
+
+struct Run {
+    // Fraction of the link's capacity the flow used
+    utilization: f64,
+    p50_delay: u64,
+    p95_delay: u64,
+}
+
+fn run(trace: &Trace, reno: bool) -> Run {
+    let bottleneck = Bottleneck::with_trace(trace.clone(), 400, AqmConf::DropTail);
+    let mut sim = Simulator::new(bottleneck, 40_000);
+    let flow = match reno {
+        true => sim.add_reno_flow(0),
+        false => sim.add_flow(config(DeltaModeConf::NoTCP), 0),
+    };
+    sim.run_until(END);
+    let from = 10_000_000;
+    let delivered = sim.throughput(flow, from, END) * (END - from) as f64 / 1e6;
+    Run {
+        utilization: delivered / trace.capacity(from, END),
+        p50_delay: sim.queueing_delay_percentile(from, END, 0.5),
+        p95_delay: sim.queueing_delay_percentile(from, END, 0.95),
+    }
+}
+
+#[test]
+fn trace_parsing() {
+    let t = Trace::parse("1\n1\n\n3\n").unwrap();
+    assert_eq!(t.period(), 3_000);
+    assert_eq!(t.capacity(0, 3_000), 2. * 1448.);
+    assert_eq!(t.capacity(0, 3_001), 3. * 1448.);
+    assert_eq!(t.capacity(1_000, 7_000), 6. * 1448.);
+    assert_eq!(t.rate(), 1448e3);
+
+    for bad in &["", "0", "1\nx\n", "2\n1\n"] {
+        let err = Trace::parse(bad).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData, "{:?}", bad);
+    }
+}
+
+// A packet per ms is the same link as a constant 11.584 Mbit/s
+#[test]
+fn constant_trace_matches_constant_rate() {
+    let t = trace("constant-12mbps");
+    assert_eq!(t.rate(), 1448e3);
+
+    let mut throughputs = Vec::new();
+    for bottleneck in [
+        Bottleneck::with_trace(t, 400, AqmConf::DropTail),
+        Bottleneck::new(11.584, 400),
+    ] {
+        let mut sim = Simulator::new(bottleneck, 40_000);
+        let flow = sim.add_flow(config(DeltaModeConf::NoTCP), 0);
+        sim.run_until(END);
+        throughputs.push(sim.throughput(flow, 10_000_000, END));
+    }
+    assert!((throughputs[0] / throughputs[1] - 1.).abs() < 0.02, "{:?}", throughputs);
+    assert!(throughputs[0] > 0.98 * 1448e3, "{:?}", throughputs);
+}
+
+// Copa keeps up as capacity changes, and holds the queue well below what a
+// buffer-filling flow does
+#[test]
+fn copa_follows_varying_capacity() {
+    for (name, min_utilization) in &[("step-12-48mbps", 0.85), ("cellular", 0.75)] {
+        let t = trace(name);
+        let copa = run(&t, false);
+        let reno = run(&t, true);
+        assert!(reno.utilization > 0.95, "{}: reno utilization {}", name, reno.utilization);
+        assert!(copa.utilization > *min_utilization, "{}: copa utilization {}", name, copa.utilization);
+        assert!(
+            copa.p50_delay < reno.p50_delay / 3,
+            "{}: p50 delay copa {} reno {}",
+            name,
+            copa.p50_delay,
+            reno.p50_delay
+        );
+        assert!(
+            copa.p95_delay < reno.p95_delay / 2,
+            "{}: p95 delay copa {} reno {}",
+            name,
+            copa.p95_delay,
+            reno.p95_delay
+        );
+    }
+}
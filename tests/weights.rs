@@ -0,0 +1,35 @@
+extern crate ccp_copa;
+extern crate portus;
+
+mod common;
+
+use ccp_copa::sim::{Bottleneck, Simulator};
+use ccp_copa::{CopaConfig, DeltaModeConf};
+use common::config;
+
+fn throughput_ratio(w1: f32, w2: f32) -> f64 {
+    let mut sim = Simulator::new(Bottleneck::new(24., 1000), 40_000);
+    let [f1, f2] = [w1, w2].map(|weight| {
+        let cfg = CopaConfig {
+            weight,
+            ..config(DeltaModeConf::NoTCP)
+        };
+        sim.add_flow(cfg, 0)
+    });
+    sim.run_until(40_000_000);
+    let t1 = sim.throughput(f1, 20_000_000, 40_000_000);
+    let t2 = sim.throughput(f2, 20_000_000, 40_000_000);
+    t2 / t1
+}
+
+#[test]
+fn equal_weights_share_equally() {
+    let ratio = throughput_ratio(1., 1.);
+    assert!(ratio > 0.8 && ratio < 1.25, "ratio {}", ratio);
+}
+
+#[test]
+fn double_weight_gets_double_share() {
+    let ratio = throughput_ratio(1., 2.);
+    assert!(ratio > 1.6 && ratio < 2.5, "ratio {}", ratio);
+}